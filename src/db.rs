@@ -1,8 +1,99 @@
+use crate::util::config::Config;
 use hyper::client::HttpConnector;
 use hyper_tls::{native_tls, HttpsConnector};
 
-pub async fn init_client() -> clickhouse::error::Result<clickhouse::Client> {
-    let database = dotenvy::var("CLICKHOUSE_DATABASE").unwrap();
+/// Column names and ClickHouse types of the `views` table, in `PageView` field order
+pub const VIEWS_COLUMNS: &[(&str, &str)] = &[
+    ("id", "UUID"),
+    ("recorded", "DateTime64(4)"),
+    ("domain", "String"),
+    ("site_path", "String"),
+    ("full_path", "String DEFAULT ''"),
+    ("referrer_domain", "String DEFAULT ''"),
+    ("from_server", "Bool"),
+    ("user_id", "UInt64"),
+    ("project_id", "UInt64"),
+    ("ip", "IPv6"),
+    ("country", "String"),
+    ("city", "Nullable(String)"),
+    ("asn", "UInt32 DEFAULT 0"),
+    ("asn_org", "String DEFAULT ''"),
+    ("user_agent", "String"),
+    ("user_agent_kind", "LowCardinality(String) DEFAULT ''"),
+    ("headers", "Array(Tuple(String, String))"),
+    ("sample_weight", "Float64 DEFAULT 1"),
+    ("owner", "Bool DEFAULT false"),
+    ("respected_dnt", "Bool DEFAULT false"),
+];
+
+/// Column names and ClickHouse types of the `downloads` table, in `Download` field order
+pub const DOWNLOADS_COLUMNS: &[(&str, &str)] = &[
+    ("id", "UUID"),
+    ("recorded", "DateTime64(4)"),
+    ("domain", "String"),
+    ("site_path", "String"),
+    ("full_path", "String DEFAULT ''"),
+    ("user_id", "UInt64"),
+    ("project_id", "UInt64"),
+    ("version_id", "UInt64"),
+    ("ip", "IPv6"),
+    ("country", "String"),
+    ("subdivision", "LowCardinality(String) DEFAULT ''"),
+    ("asn", "UInt32 DEFAULT 0"),
+    ("asn_org", "String DEFAULT ''"),
+    ("user_agent", "String"),
+    ("user_agent_kind", "LowCardinality(String) DEFAULT ''"),
+    ("headers", "Array(Tuple(String, String))"),
+    ("range", "String DEFAULT ''"),
+    ("counted", "Bool DEFAULT true"),
+    ("owner", "Bool DEFAULT false"),
+];
+
+/// Column names and ClickHouse types of the `download_sessions` table, in `DownloadSession` field
+/// order
+pub const DOWNLOAD_SESSIONS_COLUMNS: &[(&str, &str)] = &[
+    ("id", "UUID"),
+    ("recorded", "DateTime64(4)"),
+    ("project_id", "UInt64"),
+    ("version_id", "UInt64"),
+    ("completed", "Bool"),
+];
+
+/// Column names and ClickHouse types of the `revenue` table, in `Revenue` field order
+pub const REVENUE_COLUMNS: &[(&str, &str)] = &[
+    ("id", "UUID"),
+    ("recorded", "DateTime64(4)"),
+    ("project_id", "UInt64"),
+    ("amount", "Float64"),
+];
+
+/// Column names and ClickHouse types of the `multiplier_audits` table, in `MultiplierAudit` field
+/// order
+pub const MULTIPLIER_AUDITS_COLUMNS: &[(&str, &str)] = &[
+    ("id", "UUID"),
+    ("recorded", "DateTime64(4)"),
+    ("start", "DateTime64(4)"),
+    ("end", "DateTime64(4)"),
+    ("sum", "UInt64"),
+    ("values", "Array(Tuple(UInt64, UInt64))"),
+    ("version", "String"),
+    ("config", "String"),
+];
+
+/// Column names and ClickHouse types of the `webhooks` table, in `Webhook` field order
+pub const WEBHOOKS_COLUMNS: &[(&str, &str)] = &[
+    ("id", "UUID"),
+    ("recorded", "DateTime64(4)"),
+    ("project_id", "UInt64"),
+    ("url", "String"),
+    ("secret", "String"),
+    ("enabled", "Bool"),
+    ("failures", "UInt32"),
+    ("last_sent", "DateTime64(4)"),
+];
+
+pub async fn init_client(config: &Config) -> clickhouse::error::Result<clickhouse::Client> {
+    let database = &config.clickhouse_database;
 
     let client = {
         let mut http_connector = HttpConnector::new();
@@ -13,9 +104,9 @@ pub async fn init_client() -> clickhouse::error::Result<clickhouse::Client> {
         let hyper_client = hyper::client::Client::builder().build(https_connector);
 
         clickhouse::Client::with_http_client(hyper_client)
-            .with_url(dotenvy::var("CLICKHOUSE_URL").unwrap())
-            .with_user(dotenvy::var("CLICKHOUSE_USER").unwrap())
-            .with_password(dotenvy::var("CLICKHOUSE_PASSWORD").unwrap())
+            .with_url(&config.clickhouse_url)
+            .with_user(&config.clickhouse_user)
+            .with_password(&config.clickhouse_password)
     };
 
     client
@@ -23,57 +114,63 @@ pub async fn init_client() -> clickhouse::error::Result<clickhouse::Client> {
         .execute()
         .await?;
 
-    client
-        .query(&format!(
-            "
-            CREATE TABLE IF NOT EXISTS {database}.views
-            (
-                id UUID,
-                recorded DateTime64(4),
-                domain String,
-                site_path String,
-                from_server Bool,
-
-                user_id UInt64,
-                project_id UInt64,
-
-                ip IPv6,
-                country String,
-                user_agent String,
-                headers Array(Tuple(String, String)),
-            )
-            ENGINE = MergeTree()
-            PRIMARY KEY (id, recorded)
-            "
-        ))
-        .execute()
-        .await?;
+    for (table, columns) in [
+        ("views", VIEWS_COLUMNS),
+        ("downloads", DOWNLOADS_COLUMNS),
+        ("download_sessions", DOWNLOAD_SESSIONS_COLUMNS),
+        ("revenue", REVENUE_COLUMNS),
+        ("multiplier_audits", MULTIPLIER_AUDITS_COLUMNS),
+        ("webhooks", WEBHOOKS_COLUMNS),
+    ] {
+        let definitions = columns
+            .iter()
+            .map(|(name, kind)| format!("{name} {kind}"))
+            .collect::<Vec<_>>();
 
-    client
-        .query(&format!(
-            "
-            CREATE TABLE IF NOT EXISTS {database}.downloads
-            (
-                id UUID,
-                recorded DateTime64(4),
-                domain String,
-                site_path String,
-
-                user_id UInt64,
-                project_id UInt64,
-                version_id UInt64,
-
-                ip IPv6,
-                country String,
-                user_agent String,
-                headers Array(Tuple(String, String)),
-            )
-            ENGINE = MergeTree()
-            PRIMARY KEY (id, recorded)
-            "
-        ))
-        .execute()
-        .await?;
+        client
+            .query(&format!(
+                "
+                CREATE TABLE IF NOT EXISTS {database}.{table}
+                (
+                    {}
+                )
+                ENGINE = MergeTree()
+                PRIMARY KEY (id, recorded)
+                ",
+                definitions.join(",\n")
+            ))
+            .execute()
+            .await?;
+
+        // Tables created by older versions are missing columns added since
+        for definition in definitions {
+            client
+                .query(&format!(
+                    "ALTER TABLE {database}.{table} ADD COLUMN IF NOT EXISTS {definition}"
+                ))
+                .execute()
+                .await?;
+        }
+    }
 
     Ok(client.with_database(database))
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::util::config::tests::config_from;
+    use uuid::Uuid;
+
+    /// Connects to the ClickHouse at `CLICKHOUSE_TEST_URL` (e.g. `http://localhost:8123` from
+    /// docker-compose.yml) as `default` without a password, with every table created in a fresh
+    /// database. Without the variable, tests that need ClickHouse return early instead.
+    pub(crate) async fn test_client() -> Option<clickhouse::Client> {
+        let url = std::env::var("CLICKHOUSE_TEST_URL").ok()?;
+        let database = format!("ariadne_test_{}", Uuid::new_v4().simple());
+
+        let (config, _) =
+            config_from(&[("CLICKHOUSE_URL", &url), ("CLICKHOUSE_DATABASE", &database)]);
+
+        Some(super::init_client(&config).await.unwrap())
+    }
+}
@@ -4,30 +4,58 @@ mod routes;
 mod scheduled;
 mod util;
 
+use crate::models::download_sessions::DownloadSession;
+use crate::routes::admin;
 use crate::routes::index;
 use crate::routes::ingest;
+use crate::routes::metrics;
 use crate::routes::query;
 use crate::scheduled::analytics::AnalyticsQueue;
-use crate::util::env::{parse_strings_from_var, parse_var};
+use crate::scheduled::download_dedup::DownloadDedup;
+use crate::scheduled::download_sessions::PendingDownloads;
+use crate::scheduled::download_throttle::DownloadThrottle;
+use crate::scheduled::ratelimit::{self, RateLimitQueue, RateLimitStore};
+use crate::util::auth::Authorizer;
+use crate::util::cache::TtlCache;
+use crate::util::circuit::StorageCircuit;
+use crate::util::config::Config;
+use crate::util::cors::AllowedOrigins;
+use crate::util::env::ConfigReport;
+use crate::util::events::EventPublisher;
+use crate::util::flags::FeatureFlags;
+use crate::util::guards;
+use crate::util::labrinth;
+use crate::util::limiter::ConcurrencyLimiter;
+use crate::util::metrics::Metrics;
+use crate::util::paths::SitePaths;
+use crate::util::prefetch::PrefetchDetector;
+use crate::util::proxies::TrustedProxies;
+use crate::util::request_id;
+use crate::util::state::AppState;
+use crate::util::teams::ProjectTeams;
+use crate::util::users::UserIds;
 use actix_cors::Cors;
 use actix_web::{http, web, App, HttpServer};
+use dashmap::DashMap;
 use log::{error, info, warn};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenvy::dotenv().ok();
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    if check_env_vars() {
-        error!("Some environment variables are missing!");
+    let mut report = ConfigReport::default();
+    let config = Arc::new(Config::from_env(&mut report));
+    if !report.is_ok() {
+        error!("Invalid configuration, {}", report);
 
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "Missing required environment variables",
+        return Err(std::io::Error::other(
+            "Invalid or missing environment variables",
         ));
     }
+    guards::set_admin_keys(config.admin_keys.clone());
 
     let sentry = sentry::init(sentry::ClientOptions {
         release: sentry::release_name!(),
@@ -42,63 +70,306 @@ async fn main() -> std::io::Result<()> {
     }
 
     info!("Initializing database connection");
-    let client = db::init_client().await.unwrap();
+    let client = db::init_client(&config).await.unwrap();
 
-    let mut scheduler = scheduled::scheduler::Scheduler::new();
+    let mut scheduler = scheduled::scheduler::Scheduler::new(config.scheduler_jitter);
 
-    info!("Downloading MaxMind GeoLite2 country database");
-    let reader = Arc::new(scheduled::maxmind::MaxMindIndexer::new().await.unwrap());
-    {
+    let reader = Arc::new(match &config.maxmind_local_db {
+        Some(path) => {
+            info!("Loading local MaxMind database");
+            scheduled::maxmind::MaxMindIndexer::from_file(path)
+        }
+        None => {
+            info!("Loading MaxMind GeoLite2 databases");
+            scheduled::maxmind::MaxMindIndexer::new(&config).await
+        }
+    });
+    // A local database is managed by the deployer, so it is never downloaded
+    if config.maxmind_local_db.is_none() {
         let reader_ref = reader.clone();
         scheduler.run(Duration::from_secs(60 * 60 * 24), move || {
             let reader_ref = reader_ref.clone();
 
             async move {
-                info!("Downloading MaxMind GeoLite2 country database");
+                info!("Downloading MaxMind GeoLite2 databases");
                 let result = reader_ref.index().await;
                 if let Err(e) = result {
-                    warn!(
-                        "Downloading MaxMind GeoLite2 country database failed: {:?}",
-                        e
-                    );
+                    warn!("Downloading MaxMind GeoLite2 databases failed: {:?}", e);
                 }
-                info!("Done downloading MaxMind GeoLite2 country database");
+                info!("Done downloading MaxMind GeoLite2 databases");
+            }
+        });
+    }
+
+    let feature_flags = Arc::new(FeatureFlags::new());
+    {
+        let feature_flags_ref = feature_flags.clone();
+        scheduler.run(Duration::from_secs(60), move || {
+            let feature_flags_ref = feature_flags_ref.clone();
+
+            async move {
+                feature_flags_ref.refresh();
             }
         });
     }
 
-    let analytics_queue = Arc::new(AnalyticsQueue::new());
+    let metrics = Arc::new(Metrics::new().unwrap());
+
+    let storage_circuit = Arc::new(StorageCircuit::new(
+        config.storage_failure_threshold,
+        config.storage_down_download_cap,
+    ));
+
+    let analytics_queue = Arc::new(AnalyticsQueue::new(
+        config.queue_max_entries,
+        config.queue_flush_threshold,
+    ));
+
+    // Data points still queued at the last shutdown are restored before any new ones come in
+    if let Some(path) = &config.queue_persist_path {
+        match analytics_queue.restore(path) {
+            Ok(count) => info!("Restored {} persisted analytics data points", count),
+            Err(e) => warn!("Restoring the persisted analytics queue failed: {}", e),
+        }
+    }
     {
         let client_ref = client.clone();
         let analytics_queue_ref = analytics_queue.clone();
-        scheduler.run(Duration::from_secs(60 * 5), move || {
+        let storage_circuit_ref = storage_circuit.clone();
+        let metrics_ref = metrics.clone();
+        let flush = move || {
             let client_ref = client_ref.clone();
             let analytics_queue_ref = analytics_queue_ref.clone();
+            let storage_circuit_ref = storage_circuit_ref.clone();
+            let metrics_ref = metrics_ref.clone();
 
             async move {
                 info!("Indexing analytics queue");
+                let started = Instant::now();
                 let result = analytics_queue_ref.index(client_ref).await;
+                storage_circuit_ref.record_flush(result.is_ok());
+                metrics_ref.observe_flush(
+                    started.elapsed(),
+                    result.as_ref().err().map(|e| e.kind.as_str()),
+                );
                 if let Err(e) = result {
-                    warn!("Indexing analytics queue failed: {:?}", e);
+                    warn!("Indexing analytics queue failed: {}", e);
                 }
                 info!("Done indexing analytics queue");
             }
+        };
+
+        // The timed flush is a floor - bursts reaching `QUEUE_FLUSH_THRESHOLD` flush sooner
+        scheduler.run(Duration::from_secs(60 * 5), flush.clone());
+
+        let analytics_queue_ref = analytics_queue.clone();
+        scheduler.run_on(
+            move || {
+                let analytics_queue_ref = analytics_queue_ref.clone();
+
+                async move { analytics_queue_ref.flush_requested().await }
+            },
+            flush,
+        );
+    }
+
+    if config.webhook_delivery {
+        let client_ref = client.clone();
+        scheduler.run(Duration::from_secs(60 * 60), move || {
+            let client_ref = client_ref.clone();
+
+            async move {
+                info!("Delivering webhook summaries");
+                if let Err(e) = scheduled::webhooks::deliver_summaries(&client_ref).await {
+                    warn!("Delivering webhook summaries failed: {}", e);
+                }
+                info!("Done delivering webhook summaries");
+            }
+        });
+    }
+
+    let pending_downloads = Arc::new(PendingDownloads::new(config.download_session_timeout));
+    {
+        let pending_downloads_ref = pending_downloads.clone();
+        let analytics_queue_ref = analytics_queue.clone();
+        scheduler.run(Duration::from_secs(60), move || {
+            let pending_downloads_ref = pending_downloads_ref.clone();
+            let analytics_queue_ref = analytics_queue_ref.clone();
+
+            async move {
+                for download in pending_downloads_ref.expire() {
+                    analytics_queue_ref
+                        .add_download_session(DownloadSession::new(&download, false))
+                        .await;
+                }
+            }
+        });
+    }
+
+    let download_throttle = Arc::new(DownloadThrottle::new(config.download_min_interval));
+    {
+        let download_throttle_ref = download_throttle.clone();
+        scheduler.run(Duration::from_secs(60), move || {
+            let download_throttle_ref = download_throttle_ref.clone();
+
+            async move {
+                download_throttle_ref.prune();
+            }
+        });
+    }
+
+    let download_dedup = Arc::new(DownloadDedup::new(
+        config.rate_limit_pepper.clone(),
+        config.download_dedup_window,
+    ));
+    {
+        let download_dedup_ref = download_dedup.clone();
+        scheduler.run(Duration::from_secs(60), move || {
+            let download_dedup_ref = download_dedup_ref.clone();
+
+            async move {
+                download_dedup_ref.prune();
+            }
+        });
+    }
+
+    let lifetime_totals_cache = Arc::new(TtlCache::<(u64, bool), query::ProjectTotals>::new(
+        Duration::from_secs(60 * 5),
+    ));
+    {
+        let lifetime_totals_cache_ref = lifetime_totals_cache.clone();
+        scheduler.run(Duration::from_secs(60 * 5), move || {
+            let lifetime_totals_cache_ref = lifetime_totals_cache_ref.clone();
+
+            async move {
+                lifetime_totals_cache_ref.prune();
+            }
+        });
+    }
+
+    let labrinth_client = labrinth::client(config.labrinth_timeout);
+
+    let user_ids = Arc::new(UserIds::new(
+        Duration::from_secs(60 * 5),
+        labrinth_client.clone(),
+        config.clone(),
+        metrics.clone(),
+    ));
+    {
+        let user_ids_ref = user_ids.clone();
+        scheduler.run(Duration::from_secs(60 * 5), move || {
+            let user_ids_ref = user_ids_ref.clone();
+
+            async move {
+                user_ids_ref.prune();
+            }
+        });
+    }
+
+    let project_teams = Arc::new(ProjectTeams::new(
+        Duration::from_secs(60 * 5),
+        labrinth_client.clone(),
+        config.clone(),
+        metrics.clone(),
+    ));
+    {
+        let project_teams_ref = project_teams.clone();
+        scheduler.run(Duration::from_secs(60 * 5), move || {
+            let project_teams_ref = project_teams_ref.clone();
+
+            async move {
+                project_teams_ref.prune();
+            }
         });
     }
 
+    let rate_limit_store = match &config.redis_url {
+        Some(url) => {
+            info!("Connecting to Redis for rate limiting");
+            // Falls back to per-replica limits rather than refusing to start while Redis is down
+            match RateLimitStore::redis(url).await {
+                Ok(store) => store,
+                Err(e) => {
+                    error!("Connecting to Redis failed, rate limiting in memory: {}", e);
+                    RateLimitStore::Memory(DashMap::new())
+                }
+            }
+        }
+        None => RateLimitStore::Memory(DashMap::new()),
+    };
+
+    let rate_limit_queue = Arc::new(RateLimitQueue::new(
+        rate_limit_store,
+        config.rate_limit_pepper.clone(),
+        config.rate_limit_max_views,
+    ));
+    {
+        let rate_limit_queue_ref = rate_limit_queue.clone();
+        scheduler.run(ratelimit::WINDOW, move || {
+            let rate_limit_queue_ref = rate_limit_queue_ref.clone();
+
+            async move {
+                rate_limit_queue_ref.index();
+            }
+        });
+    }
+
+    let events = Arc::new(EventPublisher::new(&config, metrics.clone()).await);
+
+    let allowed_origins = Arc::new(AllowedOrigins::new(&config));
+    let prefetch_detector = Arc::new(PrefetchDetector::new(&config));
+    let trusted_proxies = Arc::new(TrustedProxies::new(&config));
+    let site_paths = Arc::new(SitePaths::new(&config));
+
+    let labrinth_limiter = Arc::new(ConcurrencyLimiter::new(
+        config.labrinth_max_in_flight,
+        config.labrinth_queue_timeout,
+    ));
+
+    // Auth checks wait longer than ingest, as a query can't be answered without one
+    let authorizer = Arc::new(Authorizer::new(
+        Duration::from_secs(60),
+        ConcurrencyLimiter::new(
+            config.labrinth_auth_max_in_flight,
+            config.labrinth_auth_queue_timeout,
+        ),
+        labrinth_client.clone(),
+        config.clone(),
+        metrics.clone(),
+    ));
+    {
+        let authorizer_ref = authorizer.clone();
+        scheduler.run(Duration::from_secs(60), move || {
+            let authorizer_ref = authorizer_ref.clone();
+
+            async move {
+                authorizer_ref.prune();
+            }
+        });
+    }
+
+    let state = web::Data::new(AppState {
+        config: config.clone(),
+        metrics: metrics.clone(),
+        analytics_queue: analytics_queue.clone(),
+        rate_limit_queue,
+        labrinth_limiter,
+        user_ids,
+        project_teams,
+    });
+
     info!("Starting Actix HTTP server!");
 
-    HttpServer::new(move || {
+    let analytics_queue_ref = analytics_queue.clone();
+    let client_ref = client.clone();
+    let result = HttpServer::new(move || {
+        let allowed_origins_ref = allowed_origins.clone();
+
         App::new()
             .wrap(
                 Cors::default()
-                    .allowed_origin_fn(|origin, _req_head| {
-                        let allowed_origins =
-                            parse_strings_from_var("CORS_ALLOWED_ORIGINS").unwrap_or_default();
-
-                        allowed_origins.contains(&"*".to_string())
-                            || allowed_origins
-                                .contains(&origin.to_str().unwrap_or_default().to_string())
+                    .allowed_origin_fn(move |origin, _req_head| {
+                        allowed_origins_ref.is_allowed(origin.to_str().unwrap_or_default())
                     })
                     .allowed_methods(vec!["GET", "POST"])
                     .allowed_headers(vec![
@@ -108,47 +379,79 @@ async fn main() -> std::io::Result<()> {
                     ])
                     .max_age(3600),
             )
-            .app_data(web::Data::new(analytics_queue.clone()))
+            .app_data(web::JsonConfig::default().error_handler(routes::extractor_error))
+            .app_data(web::QueryConfig::default().error_handler(routes::extractor_error))
+            .app_data(state.clone())
             .app_data(web::Data::new(client.clone()))
             .app_data(web::Data::new(reader.clone()))
+            .app_data(web::Data::new(allowed_origins.clone()))
+            .app_data(web::Data::new(authorizer.clone()))
+            .app_data(web::Data::new(feature_flags.clone()))
+            .app_data(web::Data::new(pending_downloads.clone()))
+            .app_data(web::Data::new(download_throttle.clone()))
+            .app_data(web::Data::new(download_dedup.clone()))
+            .app_data(web::Data::new(lifetime_totals_cache.clone()))
+            .app_data(web::Data::new(prefetch_detector.clone()))
+            .app_data(web::Data::new(trusted_proxies.clone()))
+            .app_data(web::Data::new(site_paths.clone()))
+            .app_data(web::Data::new(storage_circuit.clone()))
+            .app_data(web::Data::new(events.clone()))
+            .wrap_fn(request_id::with_request_id)
             .wrap(sentry_actix::Sentry::new())
             .service(index::index_get)
+            .service(index::health_get)
+            .service(metrics::metrics_get)
             .service(query::multipliers_query)
+            .service(query::multipliers_range_query)
+            .service(query::project_multiplier_query)
+            .service(query::days_of_week_query)
+            .service(query::visitors_query)
+            .service(query::suspicious_projects_query)
+            .service(query::downloads_map_query)
+            .service(query::download_completion_query)
+            .service(query::ranged_downloads_query)
+            .service(query::lifetime_totals_query)
+            .service(query::daily_metrics_query)
+            .service(query::logged_in_query)
+            .service(query::cohort_query)
+            .service(query::downloads_query)
+            .service(query::countries_query)
+            .service(query::summary_query)
+            .service(query::revenue_query)
             .service(ingest::downloads_ingest)
+            .service(ingest::bulk_downloads_ingest)
+            .service(ingest::download_start_ingest)
+            .service(ingest::download_complete_ingest)
             .service(ingest::page_view_ingest)
+            .service(ingest::revenue_ingest)
+            .service(admin::queue_get)
+            .service(admin::flush_post)
+            .service(admin::schema_get)
+            .service(admin::webhook_register)
+            .service(admin::webhooks_get)
+            .service(admin::webhook_remove)
     })
-    .bind(dotenvy::var("BIND_ADDR").unwrap())?
+    .bind(&config.bind_addr)?
     .run()
-    .await
-}
+    .await;
 
-// This is so that env vars not used immediately don't panic at runtime
-fn check_env_vars() -> bool {
-    let mut failed = false;
-
-    fn check_var<T: std::str::FromStr>(var: &'static str) -> bool {
-        let check = parse_var::<T>(var).is_none();
-        if check {
-            warn!(
-                "Variable `{}` missing in dotenv or not of type `{}`",
-                var,
-                std::any::type_name::<T>()
-            );
-        }
-        check
-    }
+    // The server stops gracefully on SIGTERM and SIGINT, after which nothing else is queued.
+    // Scheduled runs are let finish first, so none are cut off mid-insert.
+    info!("Stopping scheduled tasks");
+    scheduler.shutdown().await;
 
-    if parse_strings_from_var("CORS_ALLOWED_ORIGINS").is_none() {
-        warn!("Variable `CORS_ALLOWED_ORIGINS` missing in dotenv or not a json array of strings");
-        failed |= true;
+    // Persisted data points are flushed on the next startup, so they survive ClickHouse being down
+    if let Some(path) = &config.queue_persist_path {
+        match analytics_queue_ref.persist(path) {
+            Ok(count) => info!("Persisted {} queued analytics data points", count),
+            Err(e) => error!("Persisting the analytics queue failed: {}", e),
+        }
+    } else {
+        match analytics_queue_ref.index(client_ref).await {
+            Ok(_) => info!("Flushed the analytics queue"),
+            Err(e) => error!("Flushing the analytics queue failed: {}", e),
+        }
     }
 
-    failed |= check_var::<String>("CLICKHOUSE_URL");
-    failed |= check_var::<String>("CLICKHOUSE_USER");
-    failed |= check_var::<String>("CLICKHOUSE_PASSWORD");
-    failed |= check_var::<String>("CLICKHOUSE_DATABASE");
-
-    failed |= check_var::<String>("MAXMIND_LICENSE_KEY");
-
-    failed
+    result
 }
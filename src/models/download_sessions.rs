@@ -0,0 +1,48 @@
+use crate::models::downloads::Download;
+use crate::util::time;
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use uuid::Uuid;
+
+/// The resolution of a two-phase (start/complete) download
+#[derive(Row, Serialize, Deserialize, Clone)]
+pub struct DownloadSession {
+    // The ID of the started download
+    #[serde(with = "uuid::serde::compact")]
+    pub id: Uuid,
+    // When the session was completed or expired
+    pub recorded: i64,
+
+    pub project_id: u64,
+    pub version_id: u64,
+
+    // false if the session expired before being completed
+    pub completed: bool,
+}
+
+impl DownloadSession {
+    pub fn new(download: &Download, completed: bool) -> Self {
+        DownloadSession {
+            id: download.id,
+            recorded: time::record_now(),
+            project_id: download.project_id,
+            version_id: download.version_id,
+            completed,
+        }
+    }
+}
+
+impl PartialEq<Self> for DownloadSession {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for DownloadSession {}
+
+impl Hash for DownloadSession {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
@@ -1,18 +1,20 @@
 use clickhouse::Row;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
 use std::net::Ipv6Addr;
 use uuid::Uuid;
 
-#[derive(Row, Serialize, Clone)]
+#[derive(Row, Serialize, Deserialize, Clone)]
 pub struct Download {
     #[serde(with = "uuid::serde::compact")]
     pub id: Uuid,
     pub recorded: i64,
     pub domain: String,
     pub site_path: String,
+    // The uncut site path when full paths are stored, otherwise empty
+    pub full_path: String,
 
-    // Modrinth User ID for logged in users (unused atm), default 0
+    // Modrinth User ID for logged in users, default 0
     pub user_id: u64,
     // default is 0 if unknown
     pub project_id: u64,
@@ -23,8 +25,28 @@ pub struct Download {
     // (ex: download botting).
     pub ip: Ipv6Addr,
     pub country: String,
+    // ISO code of the largest subdivision (e.g. state) in `country`. Only looked up with
+    // `MAXMIND_ENABLE_CITY`, empty if disabled or unknown. Downloads are never located more
+    // precisely than this.
+    pub subdivision: String,
+    // Autonomous system the IP belongs to, 0 and empty if unknown
+    pub asn: u32,
+    pub asn_org: String,
     pub user_agent: String,
+    // `UserAgentKind` of the user agent
+    pub user_agent_kind: String,
     pub headers: Vec<(String, String)>,
+
+    // The raw `Range` or `Content-Range` of a ranged request, otherwise empty
+    pub range: String,
+
+    // false if the download was a prefetch, continued an earlier ranged request, repeated a recent
+    // download of the version from the same IP or came too soon after another from the same IP,
+    // in which case it is only stored for investigation and left out of download counts
+    pub counted: bool,
+
+    // true if the downloader is on the team of the downloaded project
+    pub owner: bool,
 }
 
 impl PartialEq<Self> for Download {
@@ -1,2 +1,6 @@
+pub mod download_sessions;
 pub mod downloads;
+pub mod multiplier_audits;
+pub mod revenue;
 pub mod views;
+pub mod webhooks;
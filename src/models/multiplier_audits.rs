@@ -0,0 +1,24 @@
+use clickhouse::Row;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// A record of a payout multiplier computation, so a payout can be audited and reproduced later
+#[derive(Row, Serialize)]
+pub struct MultiplierAudit {
+    #[serde(with = "uuid::serde::compact")]
+    pub id: Uuid,
+    // When the multipliers were computed
+    pub recorded: i64,
+    // The range of views the multipliers were computed over
+    pub start: i64,
+    pub end: i64,
+
+    pub sum: u64,
+    // (project ID, weighted page views)
+    pub values: Vec<(u64, u64)>,
+
+    // The ariadne version that computed the multipliers
+    pub version: String,
+    // JSON of the settings that affect the multipliers, like the active feature flags
+    pub config: String,
+}
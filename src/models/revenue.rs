@@ -0,0 +1,30 @@
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use uuid::Uuid;
+
+/// Revenue attributed to a project, e.g. from ads shown on its pages
+#[derive(Row, Serialize, Deserialize, Clone)]
+pub struct Revenue {
+    #[serde(with = "uuid::serde::compact")]
+    pub id: Uuid,
+    pub recorded: i64,
+
+    pub project_id: u64,
+    // Never negative
+    pub amount: f64,
+}
+
+impl PartialEq<Self> for Revenue {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Revenue {}
+
+impl Hash for Revenue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
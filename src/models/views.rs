@@ -1,19 +1,23 @@
 use clickhouse::Row;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
 use std::net::Ipv6Addr;
 use uuid::Uuid;
 
-#[derive(Row, Serialize, Clone)]
+#[derive(Row, Serialize, Deserialize, Clone)]
 pub struct PageView {
     #[serde(with = "uuid::serde::compact")]
     pub id: Uuid,
     pub recorded: i64,
     pub domain: String,
     pub site_path: String,
+    // The uncut site path when full paths are stored, otherwise empty
+    pub full_path: String,
+    // Host of the page linking to the viewed one, without its path or query. Empty if unknown.
+    pub referrer_domain: String,
     pub from_server: bool,
 
-    // Modrinth User ID for logged in users (unused atm)
+    // Modrinth User ID for logged in users, default 0
     pub user_id: u64,
     // Modrinth Project ID (used for payouts)
     pub project_id: u64,
@@ -22,8 +26,25 @@ pub struct PageView {
     // (ex: page view botting).
     pub ip: Ipv6Addr,
     pub country: String,
+    // Only looked up with `MAXMIND_ENABLE_CITY`, `None` if disabled or unknown
+    pub city: Option<String>,
+    // Autonomous system the IP belongs to, 0 and empty if unknown
+    pub asn: u32,
+    pub asn_org: String,
     pub user_agent: String,
+    // `UserAgentKind` of the user agent
+    pub user_agent_kind: String,
     pub headers: Vec<(String, String)>,
+
+    // How many page views this row stands for, greater than 1 when views are sampled
+    pub sample_weight: f64,
+
+    // true if the viewer is on the team of the viewed project
+    pub owner: bool,
+
+    // true if the viewer sent `DNT: 1` or `Sec-GPC: 1`, in which case the IP, geolocation, user
+    // agent, headers, user ID and referrer aren't stored
+    pub respected_dnt: bool,
 }
 
 impl PartialEq<Self> for PageView {
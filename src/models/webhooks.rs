@@ -0,0 +1,24 @@
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A state of a project's analytics webhook. Every change appends a row, and the latest row of a
+/// project is its current state.
+#[derive(Row, Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    #[serde(with = "uuid::serde::compact")]
+    pub id: Uuid,
+    pub recorded: i64,
+
+    pub project_id: u64,
+    pub url: String,
+    // The key payloads are signed with
+    pub secret: String,
+
+    // false once removed, or after too many failed deliveries
+    pub enabled: bool,
+    // Consecutive failed delivery attempts
+    pub failures: u32,
+    // The start of the last day a summary was delivered for, 0 if none was yet
+    pub last_sent: i64,
+}
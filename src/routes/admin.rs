@@ -0,0 +1,252 @@
+use crate::db::{
+    DOWNLOADS_COLUMNS, DOWNLOAD_SESSIONS_COLUMNS, MULTIPLIER_AUDITS_COLUMNS, REVENUE_COLUMNS,
+    VIEWS_COLUMNS, WEBHOOKS_COLUMNS,
+};
+use crate::models::download_sessions::DownloadSession;
+use crate::models::downloads::Download;
+use crate::models::multiplier_audits::MultiplierAudit;
+use crate::models::revenue::Revenue;
+use crate::models::views::PageView;
+use crate::models::webhooks::Webhook;
+use crate::routes::ApiError;
+use crate::scheduled::webhooks;
+use crate::util::base62::parse_base62;
+use crate::util::circuit::StorageCircuit;
+use crate::util::guards::admin_key_guard;
+use crate::util::state::AppState;
+use crate::util::time;
+use actix_web::{delete, get, post, web, HttpResponse};
+use clickhouse::Row;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Instant;
+use url::Url;
+use uuid::Uuid;
+
+const TOP_PROJECTS: usize = 10;
+
+/// Internal route - summarizes the analytics currently buffered in memory, for debugging flushes
+#[get("v1/queue", guard = "admin_key_guard")]
+pub async fn queue_get(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(state.analytics_queue.snapshot(TOP_PROJECTS)))
+}
+
+/// Internal route - flushes the analytics queue to ClickHouse right away, e.g. before a deploy,
+/// instead of waiting for the next scheduled flush
+#[post("v1/flush", guard = "admin_key_guard")]
+pub async fn flush_post(
+    state: web::Data<AppState>,
+    client: web::Data<clickhouse::Client>,
+    storage_circuit: web::Data<Arc<StorageCircuit>>,
+) -> Result<HttpResponse, ApiError> {
+    let started = Instant::now();
+    let result = state.analytics_queue.index(client.get_ref().clone()).await;
+    storage_circuit.record_flush(result.is_ok());
+    state.metrics.observe_flush(
+        started.elapsed(),
+        result.as_ref().err().map(|e| e.kind.as_str()),
+    );
+
+    let counts = result.map_err(|e| ApiError::Clickhouse(e.source))?;
+
+    Ok(HttpResponse::Ok().json(counts))
+}
+
+/// Lists a model's columns alongside the type ariadne creates them with. A `null` type means the
+/// model has a column the table definition doesn't.
+fn model_columns<T: Row>(table_columns: &[(&str, &str)]) -> Vec<Value> {
+    T::COLUMN_NAMES
+        .iter()
+        .map(|name| {
+            json!({
+                "name": name,
+                "type": table_columns
+                    .iter()
+                    .find(|(column, _)| column == name)
+                    .map(|(_, kind)| kind),
+            })
+        })
+        .collect()
+}
+
+/// Internal route - the columns ariadne expects each table to have, to compare against the
+/// database's `system.columns`
+#[get("v1/schema", guard = "admin_key_guard")]
+pub async fn schema_get() -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(json!({
+        "views": model_columns::<PageView>(VIEWS_COLUMNS),
+        "downloads": model_columns::<Download>(DOWNLOADS_COLUMNS),
+        "download_sessions": model_columns::<DownloadSession>(DOWNLOAD_SESSIONS_COLUMNS),
+        "revenue": model_columns::<Revenue>(REVENUE_COLUMNS),
+        "multiplier_audits": model_columns::<MultiplierAudit>(MULTIPLIER_AUDITS_COLUMNS),
+        "webhooks": model_columns::<Webhook>(WEBHOOKS_COLUMNS),
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct WebhookInput {
+    project_id: String,
+    url: String,
+}
+
+/// Internal route - registers a webhook that is posted a project's downloads and views of each
+/// day, replacing the project's previous webhook. Responds with the secret the payloads are signed
+/// with.
+#[post("v1/webhooks", guard = "admin_key_guard")]
+pub async fn webhook_register(
+    client: web::Data<clickhouse::Client>,
+    input: web::Json<WebhookInput>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = parse_base62(&input.project_id)
+        .map_err(|_| ApiError::InvalidInput("invalid project ID specified!".to_string()))?;
+
+    if !Url::parse(&input.url).is_ok_and(|x| x.scheme() == "https" || x.scheme() == "http") {
+        return Err(ApiError::InvalidInput(
+            "invalid webhook URL specified!".to_string(),
+        ));
+    }
+
+    let webhook = Webhook {
+        id: Uuid::new_v4(),
+        recorded: time::record_now(),
+        project_id,
+        url: input.url.clone(),
+        secret: Uuid::new_v4().simple().to_string(),
+        enabled: true,
+        failures: 0,
+        last_sent: 0,
+    };
+    webhooks::save(&client, &webhook).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "secret": webhook.secret })))
+}
+
+/// Internal route - lists the registered webhooks, without their secrets
+#[get("v1/webhooks", guard = "admin_key_guard")]
+pub async fn webhooks_get(client: web::Data<clickhouse::Client>) -> Result<HttpResponse, ApiError> {
+    let webhooks = webhooks::current(&client).await?;
+
+    Ok(HttpResponse::Ok().json(
+        webhooks
+            .into_iter()
+            .map(|x| {
+                json!({
+                    "project_id": x.project_id.to_string(),
+                    "url": x.url,
+                    "enabled": x.enabled,
+                    "failures": x.failures,
+                })
+            })
+            .collect::<Vec<_>>(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct WebhookRemoveQuery {
+    project_id: String,
+}
+
+/// Internal route - disables a project's webhook
+#[delete("v1/webhooks", guard = "admin_key_guard")]
+pub async fn webhook_remove(
+    web::Query(query): web::Query<WebhookRemoveQuery>,
+    client: web::Data<clickhouse::Client>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = parse_base62(&query.project_id)
+        .map_err(|_| ApiError::InvalidInput("invalid project ID specified!".to_string()))?;
+
+    let webhook = webhooks::current(&client)
+        .await?
+        .into_iter()
+        .find(|x| x.project_id == project_id && x.enabled)
+        .ok_or_else(|| ApiError::InvalidInput("project has no webhook!".to_string()))?;
+
+    webhooks::save(
+        &client,
+        &Webhook {
+            id: Uuid::new_v4(),
+            recorded: time::record_now(),
+            enabled: false,
+            ..webhook
+        },
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().body(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::tests::config_from;
+    use crate::util::guards::{set_admin_keys, ADMIN_KEY_HEADER};
+    use crate::util::state::tests::app_state;
+    use actix_web::http::StatusCode;
+    use actix_web::App;
+
+    fn revenue() -> Revenue {
+        Revenue {
+            id: Uuid::new_v4(),
+            recorded: time::record_now(),
+            project_id: 1,
+            amount: 1.0,
+        }
+    }
+
+    /// Calls `v1/flush` with the admin key, with ClickHouse unreachable
+    async fn flush(
+        state: &web::Data<AppState>,
+        circuit: &Arc<StorageCircuit>,
+    ) -> (StatusCode, Value) {
+        set_admin_keys(vec!["key".to_string()]);
+        // Nothing listens on the discard port, so inserts fail to connect
+        let client = clickhouse::Client::default().with_url("http://127.0.0.1:9");
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .app_data(web::Data::new(client))
+                .app_data(web::Data::new(circuit.clone()))
+                .service(flush_post),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::post()
+            .uri("/v1/flush")
+            .insert_header((ADMIN_KEY_HEADER, "key"))
+            .to_request();
+
+        let response = actix_web::test::call_service(&app, req).await;
+        let status = response.status();
+
+        (status, actix_web::test::read_body_json(response).await)
+    }
+
+    #[actix_rt::test]
+    async fn flushes_empty_queue() {
+        let state = web::Data::new(app_state(config_from(&[]).0));
+        let circuit = Arc::new(StorageCircuit::new(1, 0));
+
+        let (status, body) = flush(&state, &circuit).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "views": 0, "downloads": 0, "download_sessions": 0, "revenue": 0 })
+        );
+        assert!(!circuit.is_open());
+    }
+
+    #[actix_rt::test]
+    async fn keeps_rows_when_flush_fails() {
+        let state = web::Data::new(app_state(config_from(&[]).0));
+        state.analytics_queue.add_revenue(revenue()).await;
+        let circuit = Arc::new(StorageCircuit::new(1, 0));
+
+        let (status, body) = flush(&state, &circuit).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["error"], "clickhouse_error");
+        assert_eq!(state.analytics_queue.len(), 1);
+        // The failure counts towards opening the storage circuit, like a scheduled flush's
+        assert!(circuit.is_open());
+    }
+}
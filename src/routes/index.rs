@@ -1,7 +1,10 @@
-use actix_web::get;
+use crate::scheduled::maxmind::MaxMindIndexer;
 use actix_web::HttpResponse;
+use actix_web::{get, web};
 use serde_json::json;
+use std::sync::Arc;
 
+/// Liveness probe - responds without checking any dependencies
 #[get("/")]
 pub async fn index_get() -> HttpResponse {
     let data = json!({
@@ -12,3 +15,76 @@ pub async fn index_get() -> HttpResponse {
 
     HttpResponse::Ok().json(data)
 }
+
+/// Readiness probe - responds 503 if ClickHouse can't be queried. Geolocation is reported too, but
+/// doesn't affect readiness as ingest works without it.
+#[get("/health")]
+pub async fn health_get(
+    client: web::Data<clickhouse::Client>,
+    maxmind: web::Data<Arc<MaxMindIndexer>>,
+) -> HttpResponse {
+    let healthy = client.query("SELECT 1").fetch_one::<u8>().await.is_ok();
+
+    let data = json!({
+        "clickhouse": if healthy { "ok" } else { "down" },
+        "geo": if maxmind.is_available().await { "ok" } else { "unavailable" },
+    });
+
+    if healthy {
+        HttpResponse::Ok().json(data)
+    } else {
+        HttpResponse::ServiceUnavailable().json(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_client;
+    use actix_web::http::StatusCode;
+    use actix_web::App;
+    use serde_json::Value;
+    use std::path::Path;
+
+    async fn health(client: clickhouse::Client) -> (StatusCode, Value) {
+        // Without a database, geolocation is unavailable
+        let maxmind = Arc::new(MaxMindIndexer::from_file(Path::new("/nonexistent")));
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(client))
+                .app_data(web::Data::new(maxmind))
+                .service(health_get),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/health")
+            .to_request();
+
+        let response = actix_web::test::call_service(&app, req).await;
+        let status = response.status();
+        (status, actix_web::test::read_body_json(response).await)
+    }
+
+    #[actix_rt::test]
+    async fn reports_clickhouse_down() {
+        // Nothing listens on the discard port, so the query fails to connect
+        let client = clickhouse::Client::default().with_url("http://127.0.0.1:9");
+
+        let (status, body) = health(client).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["clickhouse"], "down");
+        assert_eq!(body["geo"], "unavailable");
+    }
+
+    #[actix_rt::test]
+    async fn reports_clickhouse_ok() {
+        let Some(client) = test_client().await else {
+            return;
+        };
+
+        let (status, body) = health(client).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["clickhouse"], "ok");
+    }
+}
@@ -1,27 +1,53 @@
+use crate::models::download_sessions::DownloadSession;
 use crate::models::downloads::Download;
+use crate::models::revenue::Revenue;
 use crate::models::views::PageView;
 use crate::routes::ApiError;
+use crate::scheduled::download_dedup::DownloadDedup;
+use crate::scheduled::download_sessions::PendingDownloads;
+use crate::scheduled::download_throttle::DownloadThrottle;
 use crate::scheduled::maxmind::MaxMindIndexer;
 use crate::util::base62::parse_base62;
-use crate::util::env::parse_strings_from_var;
-use crate::util::guards::admin_key_guard;
-use crate::AnalyticsQueue;
+use crate::util::circuit::StorageCircuit;
+use crate::util::config::Config;
+use crate::util::cors::AllowedOrigins;
+use crate::util::events::EventPublisher;
+use crate::util::flags::FeatureFlags;
+use crate::util::guards::{admin_key_guard, is_admin_key};
+use crate::util::metrics::{Metrics, ViewOutcome};
+use crate::util::paths::SitePaths;
+use crate::util::prefetch::PrefetchDetector;
+use crate::util::proxies::TrustedProxies;
+use crate::util::state::AppState;
+use crate::util::teams::ProjectTeams;
+use crate::util::time;
+use crate::util::user_agent;
+use crate::util::users::UserIds;
+use actix_web::http::header::{HeaderName, HeaderValue};
 use actix_web::{post, web};
-use actix_web::{HttpRequest, HttpResponse};
-use chrono::Utc;
-use serde::Deserialize;
-use std::collections::HashMap;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 use url::Url;
 use uuid::Uuid;
 
+// When present on a page view, the response reports whether the view was counted
+const DEBUG_HEADER: &str = "Modrinth-Analytics-Debug";
+const OUTCOME_HEADER: &str = "modrinth-view-outcome";
+
 const FILTERED_HEADERS: &[&str] = &[
     "authorization",
     "cookie",
     "modrinth-admin",
+    "modrinth-proxy-secret",
     // we already retrieve/use these elsewhere- so they are unneeded
     "user-agent",
+    "referer",
+    "referrer",
     "cf-connecting-ip",
     "cf-ipcountry",
     "x-forwarded-for",
@@ -39,6 +65,17 @@ const FILTERED_HEADERS: &[&str] = &[
     "x-vercel-ip-country",
 ];
 
+/// The host of the page a view came from, from its `Referer` (or misspelled `Referrer`) header.
+/// Only the host is kept, as paths and queries can identify the viewer.
+fn referrer_domain(headers: &HashMap<String, String>) -> String {
+    headers
+        .get("referer")
+        .or_else(|| headers.get("referrer"))
+        .and_then(|x| Url::parse(x).ok())
+        .and_then(|x| x.host_str().map(|x| x.to_string()))
+        .unwrap_or_default()
+}
+
 fn convert_to_ip_v6(src: &str) -> Result<Ipv6Addr, AddrParseError> {
     let ip_addr: IpAddr = src.parse()?;
 
@@ -54,56 +91,570 @@ pub struct DownloadInput {
     url: String,
     project_id: String,
     version_id: String,
+    // Base62 ID of the downloading user, if logged in
+    user_id: Option<String>,
     headers: HashMap<String, String>,
 }
 
+/// The raw range of a ranged download request, from the `Range` header labrinth forwards or the
+/// CDN's `Content-Range`
+fn download_range(headers: &HashMap<String, String>) -> Option<&str> {
+    ["range", "content-range"].iter().find_map(|name| {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    })
+}
+
+/// Whether a ranged request continues a download started by an earlier request, i.e. none of its
+/// ranges begin at the first byte. A CDN can serve one download as many ranged requests, and
+/// only the one fetching the start of the file is counted, so they are counted once in total.
+fn is_range_continuation(range: &str) -> bool {
+    // `bytes=0-499,1000-` for `Range`, `bytes 0-499/1234` for `Content-Range`
+    let ranges = range
+        .trim()
+        .strip_prefix("bytes")
+        .unwrap_or(range)
+        .trim_start_matches(['=', ' ']);
+
+    !ranges.split(',').any(|x| {
+        x.split(['-', '/'])
+            .next()
+            .and_then(|start| start.trim().parse::<u64>().ok())
+            == Some(0)
+    })
+}
+
+/// Validates a download and builds its data point, leaving it to be geolocated. Prefetches and
+/// continuations of ranged requests are built as not counted.
+fn build_download(
+    input: &DownloadInput,
+    config: &Config,
+    prefetch_detector: &PrefetchDetector,
+    site_paths: &SitePaths,
+) -> Result<Download, ApiError> {
+    let url = Url::parse(&input.url)
+        .map_err(|_| ApiError::InvalidInput("invalid download URL specified!".to_string()))?;
+    let domain = url.host_str().unwrap_or_default();
+
+    // Guards against a buggy or compromised caller, as only labrinth is expected to ingest
+    if !config.allows_download_host(domain) {
+        return Err(ApiError::InvalidInput(
+            "download URL host is not allowed!".to_string(),
+        ));
+    }
+
+    let parsed_pid = parse_base62(&input.project_id)
+        .map_err(|_| ApiError::InvalidInput("invalid project ID in download URL!".to_string()))?;
+    let parsed_vid = parse_base62(&input.version_id)
+        .map_err(|_| ApiError::InvalidInput("invalid version ID in download URL!".to_string()))?;
+    let parsed_uid = input
+        .user_id
+        .as_deref()
+        .map(parse_base62)
+        .transpose()
+        .map_err(|_| ApiError::InvalidInput("invalid user ID specified!".to_string()))?;
+
+    let ip = convert_to_ip_v6(&input.ip)
+        .unwrap_or_else(|_| Ipv4Addr::new(127, 0, 0, 1).to_ipv6_mapped());
+
+    let range = download_range(&input.headers).unwrap_or_default();
+    let user_agent = input.headers.get("user-agent").cloned().unwrap_or_default();
+
+    Ok(Download {
+        id: Uuid::new_v4(),
+        recorded: time::record_now(),
+        domain: domain.to_string(),
+        site_path: site_paths.normalize(url.path()),
+        full_path: site_paths.full(url.path()),
+        user_id: parsed_uid.unwrap_or_default(),
+        project_id: parsed_pid,
+        version_id: parsed_vid,
+        ip,
+        country: String::new(),
+        subdivision: String::new(),
+        asn: 0,
+        asn_org: String::new(),
+        user_agent_kind: user_agent::classify(&user_agent).as_str().to_string(),
+        user_agent,
+        headers: input
+            .headers
+            .clone()
+            .into_iter()
+            .filter(|x| !FILTERED_HEADERS.contains(&&*x.0.to_lowercase()))
+            .collect(),
+        range: range.to_string(),
+        counted: !prefetch_detector.is_prefetch(&input.headers)
+            && (range.is_empty() || !is_range_continuation(range)),
+        owner: false,
+    })
+}
+
+/// The token labrinth forwarded from the downloader, if they were signed in
+fn auth_token(input: &DownloadInput) -> Option<&str> {
+    input
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("authorization"))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Identifies the user behind a download, if labrinth didn't already pass their ID, from the
+/// forwarded authorization token. Flags it as owner traffic if they are on the project's team.
+async fn identify_downloader(
+    download: &mut Download,
+    input: &DownloadInput,
+    user_ids: &UserIds,
+    project_teams: &ProjectTeams,
+) {
+    if download.user_id == 0 {
+        if let Some(token) = auth_token(input) {
+            download.user_id = user_ids.resolve(token).await.unwrap_or_default();
+        }
+    }
+
+    if download.user_id != 0 {
+        download.owner = project_teams
+            .is_member(&input.project_id, download.project_id, download.user_id)
+            .await
+            .unwrap_or_default();
+    }
+}
+
+/// Checks the downloaded version belongs to the download's project, if `VERIFY_DOWNLOAD_OWNERSHIP`
+/// is set. Off by default, as labrinth already knows which project a version belongs to.
+async fn verify_ownership(
+    download: &Download,
+    input: &DownloadInput,
+    config: &Config,
+    project_teams: &ProjectTeams,
+) -> Result<(), ApiError> {
+    if !config.verify_download_ownership {
+        return Ok(());
+    }
+
+    let project_id = project_teams.version_project_id(&input.version_id).await?;
+
+    check_ownership(download, project_id)
+}
+
+/// Checks the download's version belongs to `project_id`, as looked up with labrinth
+fn check_ownership(download: &Download, project_id: Option<u64>) -> Result<(), ApiError> {
+    if project_id != Some(download.project_id) {
+        return Err(ApiError::InvalidInput(
+            "version does not belong to the project!".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// How `v1/download` acknowledges a download. In both modes the input is validated before
+/// responding, so malformed downloads are always rejected with an error.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Ack {
+    /// Respond `202 Accepted` immediately and geolocate + enqueue the download in the background.
+    /// Failures after the response are not reported, and the download is lost if the process
+    /// stops before it is enqueued.
+    Fast,
+    /// Respond `204 No Content` once the download is in the analytics queue. It can still be lost
+    /// if the process stops before the queue is next flushed.
+    #[default]
+    Confirmed,
+}
+
+#[derive(Deserialize)]
+pub struct DownloadOptions {
+    #[serde(default)]
+    ack: Ack,
+}
+
 // Internal (can only be called with key) - protections are lax
-// called from labrinth- URLs guaranteed to be valid
+// called from labrinth- URLs guaranteed to be valid, though hosts are checked when
+// `DOWNLOAD_ALLOWED_HOSTS` is set
 #[post("v1/download", guard = "admin_key_guard")]
+#[allow(clippy::too_many_arguments)]
 pub async fn downloads_ingest(
+    state: web::Data<AppState>,
     maxmind: web::Data<Arc<MaxMindIndexer>>,
-    analytics_queue: web::Data<Arc<AnalyticsQueue>>,
+    download_throttle: web::Data<Arc<DownloadThrottle>>,
+    download_dedup: web::Data<Arc<DownloadDedup>>,
+    prefetch_detector: web::Data<Arc<PrefetchDetector>>,
+    site_paths: web::Data<Arc<SitePaths>>,
+    storage_circuit: web::Data<Arc<StorageCircuit>>,
+    events: web::Data<Arc<EventPublisher>>,
+    web::Query(options): web::Query<DownloadOptions>,
     url_input: web::Json<DownloadInput>,
 ) -> Result<HttpResponse, ApiError> {
-    let url = Url::parse(&url_input.url)
-        .map_err(|_| ApiError::InvalidInput("invalid download URL specified!".to_string()))?;
+    if !storage_circuit.accepts_download(state.analytics_queue.downloads_len()) {
+        return Err(ApiError::StorageUnavailable);
+    }
 
-    let parsed_pid = parse_base62(&url_input.project_id)
-        .map_err(|_| ApiError::InvalidInput("invalid project ID in download URL!".to_string()))?;
-    let parsed_vid = parse_base62(&url_input.version_id)
-        .map_err(|_| ApiError::InvalidInput("invalid version ID in download URL!".to_string()))?;
+    let url_input = url_input.into_inner();
 
-    let ip = convert_to_ip_v6(&url_input.ip)
-        .unwrap_or_else(|_| Ipv4Addr::new(127, 0, 0, 1).to_ipv6_mapped());
+    let mut download = build_download(&url_input, &state.config, &prefetch_detector, &site_paths)?;
+
+    verify_ownership(&download, &url_input, &state.config, &state.project_teams).await?;
+
+    let ip = download.ip;
+    // Repeats are checked first, so they don't hold back the project's next counted download
+    download.counted = download.counted
+        && download_dedup.count(ip, download.version_id)
+        && download_throttle.count(download.project_id, ip);
+
+    let maxmind = maxmind.get_ref().clone();
+    let events = events.get_ref().clone();
+    let enqueue = async move {
+        download.country = maxmind.try_query(ip).unwrap_or_default();
+        download.subdivision = maxmind.try_query_subdivision(ip).unwrap_or_default();
+        (download.asn, download.asn_org) = maxmind.try_query_asn(ip).unwrap_or_default();
+        identify_downloader(
+            &mut download,
+            &url_input,
+            &state.user_ids,
+            &state.project_teams,
+        )
+        .await;
+
+        events.publish("downloads", &download);
+        state.analytics_queue.add_download(download).await;
+        state.metrics.record_ingested("downloads");
+    };
+
+    match options.ack {
+        Ack::Fast => {
+            actix_web::rt::spawn(enqueue);
+
+            Ok(HttpResponse::Accepted().body(""))
+        }
+        Ack::Confirmed => {
+            enqueue.await;
+
+            Ok(HttpResponse::NoContent().body(""))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RejectedDownload {
+    index: usize,
+    error: String,
+}
+
+/// Internal route - ingests several downloads at once, e.g. every file of a modpack downloaded
+/// together. Invalid entries are skipped and reported by their index, so one bad entry doesn't
+/// lose the rest of the batch.
+#[post("v1/downloads", guard = "admin_key_guard")]
+#[allow(clippy::too_many_arguments)]
+pub async fn bulk_downloads_ingest(
+    state: web::Data<AppState>,
+    maxmind: web::Data<Arc<MaxMindIndexer>>,
+    download_throttle: web::Data<Arc<DownloadThrottle>>,
+    download_dedup: web::Data<Arc<DownloadDedup>>,
+    prefetch_detector: web::Data<Arc<PrefetchDetector>>,
+    site_paths: web::Data<Arc<SitePaths>>,
+    storage_circuit: web::Data<Arc<StorageCircuit>>,
+    events: web::Data<Arc<EventPublisher>>,
+    inputs: web::Json<Vec<DownloadInput>>,
+) -> Result<HttpResponse, ApiError> {
+    let config = &state.config;
+    let analytics_queue = &state.analytics_queue;
+    let project_teams = &state.project_teams;
+    let user_ids = &state.user_ids;
+
+    let max_size = config.download_batch_max_size;
+    if inputs.len() > max_size {
+        return Err(ApiError::InvalidInput(format!(
+            "at most {max_size} downloads can be ingested at once!"
+        )));
+    }
+
+    if !storage_circuit.accepts_download(analytics_queue.downloads_len()) {
+        return Err(ApiError::StorageUnavailable);
+    }
+
+    let concurrency = config.download_batch_concurrency;
+    let mut rejected = Vec::new();
+
+    let mut downloads = Vec::new();
+    for (index, input) in inputs.into_inner().into_iter().enumerate() {
+        match build_download(&input, config, &prefetch_detector, &site_paths) {
+            Ok(download) => downloads.push((index, input, download)),
+            Err(e) => rejected.push(RejectedDownload {
+                index,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    // Labrinth is asked about each distinct version, token and project once, `concurrency` at a
+    // time, rather than once per entry in turn
+    if config.verify_download_ownership {
+        let versions = downloads
+            .iter()
+            .map(|(_, input, _)| input.version_id.clone())
+            .collect::<HashSet<_>>();
+        let project_ids = stream::iter(versions)
+            .map(|version| async move {
+                let project_id = project_teams
+                    .version_project_id(&version)
+                    .await
+                    .map_err(|e| e.to_string());
+                (version, project_id)
+            })
+            .buffer_unordered(concurrency)
+            .collect::<HashMap<_, _>>()
+            .await;
+
+        downloads.retain(|(index, input, download)| {
+            let verified = match &project_ids[&input.version_id] {
+                Ok(project_id) => check_ownership(download, *project_id).map_err(|e| e.to_string()),
+                Err(e) => Err(e.clone()),
+            };
+
+            match verified {
+                Ok(()) => true,
+                Err(error) => {
+                    rejected.push(RejectedDownload {
+                        index: *index,
+                        error,
+                    });
+                    false
+                }
+            }
+        });
+    }
+
+    let tokens = downloads
+        .iter()
+        .filter(|(_, _, download)| download.user_id == 0)
+        .filter_map(|(_, input, _)| auth_token(input))
+        .map(|token| token.to_string())
+        .collect::<HashSet<_>>();
+    let resolved = stream::iter(tokens)
+        .map(|token| async move {
+            let user_id = user_ids.resolve(&token).await;
+            (token, user_id)
+        })
+        .buffer_unordered(concurrency)
+        .collect::<HashMap<_, _>>()
+        .await;
+
+    for (_, input, download) in &mut downloads {
+        if download.user_id == 0 {
+            download.user_id = auth_token(input)
+                .and_then(|token| resolved[token])
+                .unwrap_or_default();
+        }
+    }
+
+    let projects = downloads
+        .iter()
+        .filter(|(_, _, download)| download.user_id != 0)
+        .map(|(_, input, download)| (download.project_id, input.project_id.clone()))
+        .collect::<HashMap<_, _>>();
+    let teams = stream::iter(projects)
+        .map(|(project_id, project)| async move {
+            let members = project_teams.members(&project, project_id).await;
+            (project_id, members.unwrap_or_default())
+        })
+        .buffer_unordered(concurrency)
+        .collect::<HashMap<_, _>>()
+        .await;
+
+    // Downloads in a batch usually share an IP, so each is only geolocated once
+    let mut locations = HashMap::new();
+    let mut accepted = 0;
+
+    for (_, _, mut download) in downloads {
+        download.owner = teams
+            .get(&download.project_id)
+            .is_some_and(|members| members.contains(&download.user_id));
+
+        let ip = download.ip;
+        download.counted = download.counted
+            && download_dedup.count(ip, download.version_id)
+            && download_throttle.count(download.project_id, ip);
+
+        (
+            download.country,
+            download.subdivision,
+            download.asn,
+            download.asn_org,
+        ) = locations
+            .entry(ip)
+            .or_insert_with(|| {
+                let (asn, asn_org) = maxmind.try_query_asn(ip).unwrap_or_default();
+                (
+                    maxmind.try_query(ip).unwrap_or_default(),
+                    maxmind.try_query_subdivision(ip).unwrap_or_default(),
+                    asn,
+                    asn_org,
+                )
+            })
+            .clone();
+
+        events.publish("downloads", &download);
+        analytics_queue.add_download(download).await;
+        state.metrics.record_ingested("downloads");
+        accepted += 1;
+    }
+
+    rejected.sort_by_key(|x| x.index);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "accepted": accepted,
+        "rejected": rejected,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct DownloadStartInput {
+    session_id: String,
+    #[serde(flatten)]
+    download: DownloadInput,
+}
+
+/// Internal route - starts a two-phase download, which is only counted once it is completed
+/// through `v1/download/complete` before the session times out
+#[post("v1/download/start", guard = "admin_key_guard")]
+pub async fn download_start_ingest(
+    state: web::Data<AppState>,
+    maxmind: web::Data<Arc<MaxMindIndexer>>,
+    pending_downloads: web::Data<Arc<PendingDownloads>>,
+    prefetch_detector: web::Data<Arc<PrefetchDetector>>,
+    site_paths: web::Data<Arc<SitePaths>>,
+    input: web::Json<DownloadStartInput>,
+) -> Result<HttpResponse, ApiError> {
+    let input = input.into_inner();
+
+    let mut download = build_download(
+        &input.download,
+        &state.config,
+        &prefetch_detector,
+        &site_paths,
+    )?;
+    download.country = maxmind.try_query(download.ip).unwrap_or_default();
+    (download.asn, download.asn_org) = maxmind.try_query_asn(download.ip).unwrap_or_default();
+    identify_downloader(
+        &mut download,
+        &input.download,
+        &state.user_ids,
+        &state.project_teams,
+    )
+    .await;
+
+    pending_downloads.start(input.session_id, download);
+
+    Ok(HttpResponse::NoContent().body(""))
+}
+
+#[derive(Deserialize)]
+pub struct DownloadCompleteInput {
+    session_id: String,
+}
+
+/// Internal route - completes a two-phase download, counting it
+#[post("v1/download/complete", guard = "admin_key_guard")]
+pub async fn download_complete_ingest(
+    state: web::Data<AppState>,
+    pending_downloads: web::Data<Arc<PendingDownloads>>,
+    download_throttle: web::Data<Arc<DownloadThrottle>>,
+    download_dedup: web::Data<Arc<DownloadDedup>>,
+    storage_circuit: web::Data<Arc<StorageCircuit>>,
+    events: web::Data<Arc<EventPublisher>>,
+    input: web::Json<DownloadCompleteInput>,
+) -> Result<HttpResponse, ApiError> {
+    let analytics_queue = &state.analytics_queue;
+
+    // Rejected before the session is taken, so it can be completed again once storage is back
+    if !storage_circuit.accepts_download(analytics_queue.downloads_len()) {
+        return Err(ApiError::StorageUnavailable);
+    }
+
+    let mut download = pending_downloads
+        .complete(&input.session_id)
+        .ok_or_else(|| {
+            ApiError::InvalidInput("unknown or expired download session!".to_string())
+        })?;
+    let ip = download.ip;
+    download.counted = download.counted
+        && download_dedup.count(ip, download.version_id)
+        && download_throttle.count(download.project_id, ip);
 
     analytics_queue
-        .add_download(Download {
+        .add_download_session(DownloadSession::new(&download, true))
+        .await;
+    events.publish("downloads", &download);
+    analytics_queue.add_download(download).await;
+    state.metrics.record_ingested("downloads");
+
+    Ok(HttpResponse::NoContent().body(""))
+}
+
+#[derive(Deserialize)]
+pub struct RevenueInput {
+    project_id: String,
+    amount: f32,
+}
+
+/// Internal route - records revenue earned by a project. Like views and downloads, it is buffered
+/// in the analytics queue and flushed to the ClickHouse `revenue` table.
+#[post("v1/revenue", guard = "admin_key_guard")]
+pub async fn revenue_ingest(
+    state: web::Data<AppState>,
+    input: web::Json<RevenueInput>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = parse_base62(&input.project_id)
+        .map_err(|_| ApiError::InvalidInput("invalid project ID specified!".to_string()))?;
+
+    // Also rejects NaN
+    if !(input.amount >= 0.0 && input.amount.is_finite()) {
+        return Err(ApiError::InvalidInput(
+            "revenue amount must be a non-negative number!".to_string(),
+        ));
+    }
+
+    state
+        .analytics_queue
+        .add_revenue(Revenue {
             id: Uuid::new_v4(),
-            recorded: Utc::now().timestamp_nanos() / 100_000,
-            domain: url.host_str().unwrap_or_default().to_string(),
-            site_path: url.path().to_string(),
-            user_id: 0,
-            project_id: parsed_pid,
-            version_id: parsed_vid,
-            ip,
-            country: maxmind.query(ip).await.unwrap_or_default(),
-            user_agent: url_input
-                .headers
-                .get("user-agent")
-                .cloned()
-                .unwrap_or_default(),
-            headers: url_input
-                .headers
-                .clone()
-                .into_iter()
-                .filter(|x| !FILTERED_HEADERS.contains(&&*x.0.to_lowercase()))
-                .collect(),
+            recorded: time::record_now(),
+            project_id,
+            amount: f64::from(input.amount),
         })
         .await;
 
     Ok(HttpResponse::NoContent().body(""))
 }
 
+// Site sections whose pages are never projects, so they aren't looked up in labrinth
+const NON_PROJECT_ROUTES: &[&str] = &[
+    "user",
+    "organization",
+    "collection",
+    "settings",
+    "dashboard",
+    "auth",
+    "legal",
+    "news",
+];
+
+/// Extracts the project slug from the segments of a `/{type}/{slug}/..` page path. Labrinth
+/// resolves projects from their slug alone, so the type prefix is only a hint: a project viewed
+/// under a type it no longer has (e.g. after being recategorized from a mod to a plugin) is still
+/// attributed.
+fn project_slug<'a>(segments: &[&'a str]) -> Option<&'a str> {
+    match segments {
+        [prefix, slug, ..] if !slug.is_empty() && !NON_PROJECT_ROUTES.contains(prefix) => {
+            Some(slug)
+        }
+        _ => None,
+    }
+}
+
 #[derive(Deserialize)]
 pub struct UrlInput {
     url: String,
@@ -111,17 +662,55 @@ pub struct UrlInput {
     // These will only be sent from the Nuxt.JS server
     ip: Option<String>,
     headers: Option<HashMap<String, String>>,
+    // Base62 ID of the viewed project, if already known
+    project_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct IngestOptions {
+    // Process the data point and return it instead of enqueueing it
+    #[serde(default)]
+    validate: bool,
+}
+
+/// Whether the viewer asked not to be tracked, with `DNT: 1` or `Sec-GPC: 1`
+fn requests_dnt(headers: &HashMap<String, String>) -> bool {
+    ["dnt", "sec-gpc"]
+        .iter()
+        .any(|x| headers.get(*x).is_some_and(|x| x.trim() == "1"))
+}
+
+/// Clears everything that could identify the viewer of a view that respects DNT
+fn strip_identifying(view: &mut PageView) {
+    view.ip = Ipv6Addr::UNSPECIFIED;
+    view.country.clear();
+    view.city = None;
+    view.asn = 0;
+    view.asn_org.clear();
+    view.user_agent.clear();
+    view.headers.clear();
+    view.user_id = 0;
+    view.referrer_domain.clear();
 }
 
 //this route should be behind the cloudflare WAF to prevent non-browsers from calling it
 #[post("v1/view")]
+#[allow(clippy::too_many_arguments)]
 pub async fn page_view_ingest(
     req: HttpRequest,
+    state: web::Data<AppState>,
     maxmind: web::Data<Arc<MaxMindIndexer>>,
-    analytics_queue: web::Data<Arc<AnalyticsQueue>>,
+    allowed_origins: web::Data<Arc<AllowedOrigins>>,
+    feature_flags: web::Data<Arc<FeatureFlags>>,
+    site_paths: web::Data<Arc<SitePaths>>,
+    storage_circuit: web::Data<Arc<StorageCircuit>>,
+    trusted_proxies: web::Data<Arc<TrustedProxies>>,
+    events: web::Data<Arc<EventPublisher>>,
+    web::Query(options): web::Query<IngestOptions>,
     url_input: web::Json<UrlInput>,
 ) -> Result<HttpResponse, ApiError> {
-    let admin_key = dotenvy::var("ARIADNE_ADMIN_KEY")?;
+    let metrics = &state.metrics;
+    let project_teams = &state.project_teams;
 
     let conn_info = req.connection_info().peer_addr().map(|x| x.to_string());
 
@@ -132,20 +721,22 @@ pub async fn page_view_ingest(
         .host_str()
         .ok_or_else(|| ApiError::InvalidInput("invalid page view URL specified!".to_string()))?;
 
-    let allowed_origins = parse_strings_from_var("CORS_ALLOWED_ORIGINS").unwrap_or_default();
     if !(domain.ends_with(".modrinth.com")
         || domain == "modrinth.com"
-        || allowed_origins.contains(&"*".to_string()))
+        || allowed_origins.allows_any())
     {
-        return Err(ApiError::InvalidInput(
-            "invalid page view URL specified!".to_string(),
-        ));
+        return reject_view(
+            &req,
+            metrics,
+            ViewOutcome::DisallowedDomain,
+            ApiError::InvalidInput("page view domain is not allowed!".to_string()),
+        );
     }
 
     let from_server = req
         .headers()
         .get(crate::util::guards::ADMIN_KEY_HEADER)
-        .map(|x| x.to_str().unwrap_or_default() == &*admin_key)
+        .map(|x| is_admin_key(x.as_bytes()))
         .unwrap_or(false);
 
     let temp_headers = req
@@ -169,71 +760,512 @@ pub async fn page_view_ingest(
         temp_headers
     };
 
+    // Proxies other than Cloudflare pass the client IP in `X-Forwarded-For` instead
+    let forwarded = trusted_proxies
+        .forwarded_for(req.peer_addr(), req.headers())
+        .map(|x| x.to_string());
     let ip = convert_to_ip_v6(if from_server && url_input.ip.is_some() {
         url_input.ip.as_deref().unwrap()
-    } else if let Some(header) = headers.get("cf-connecting-ip") {
+    } else if let Some(header) = headers
+        .get("cf-connecting-ip")
+        // Anyone can set the header, so it's only believed when the request came through the proxy
+        .filter(|_| from_server || trusted_proxies.trusts(req.peer_addr(), req.headers()))
+    {
         header
+    } else if let Some(forwarded) = &forwarded {
+        forwarded
     } else {
         conn_info.as_deref().unwrap_or_default()
     })
     .unwrap_or_else(|_| Ipv4Addr::new(127, 0, 0, 1).to_ipv6_mapped());
 
+    // Used to identify logged in users, but never stored
+    let token = headers.get("authorization").cloned();
+
+    let (asn, asn_org) = maxmind.try_query_asn(ip).unwrap_or_default();
+    let user_agent = headers.get("user-agent").cloned().unwrap_or_default();
+    let respected_dnt = requests_dnt(&headers);
+
     let mut view = PageView {
         id: Uuid::new_v4(),
-        recorded: Utc::now().timestamp_nanos() / 100_000,
+        recorded: time::record_now(),
         domain: domain.to_string(),
-        site_path: url.path().to_string(),
+        site_path: site_paths.normalize(url.path()),
+        full_path: site_paths.full(url.path()),
+        referrer_domain: referrer_domain(&headers),
         from_server,
         user_id: 0,
         project_id: 0,
         ip,
-        country: maxmind.query(ip).await.unwrap_or_default(),
-        user_agent: headers.get("user-agent").cloned().unwrap_or_default(),
-        headers: headers.into_iter().filter(|x| !FILTERED_HEADERS.contains(&&*x.0)).collect(),
+        country: maxmind.try_query(ip).unwrap_or_default(),
+        city: maxmind.try_query_city(ip),
+        asn,
+        asn_org,
+        user_agent_kind: user_agent::classify(&user_agent).as_str().to_string(),
+        user_agent,
+        headers: headers
+            .into_iter()
+            .filter(|x| !FILTERED_HEADERS.contains(&&*x.0))
+            .collect(),
+        sample_weight: 1.0,
+        owner: false,
+        respected_dnt,
     };
 
-    if let Some(segments) = url.path_segments() {
-        let segments_vec = segments.collect::<Vec<_>>();
-
-        if segments_vec.len() >= 2 {
-            //todo: fetch from labrinth periodically when route exists
-            const PROJECT_TYPES: &[&str] = &[
-                "mod",
-                "modpack",
-                "plugin",
-                "resourcepack",
-                "shader",
-                "datapack",
-            ];
-
-            if PROJECT_TYPES.contains(&segments_vec[0]) {
-                #[derive(Deserialize)]
-                struct CheckResponse {
-                    id: String,
-                }
+    if !options.validate && storage_circuit.is_open() {
+        return reject_view(
+            &req,
+            metrics,
+            ViewOutcome::StorageUnavailable,
+            ApiError::StorageUnavailable,
+        );
+    }
+
+    // Checked before attribution so rate limited views don't cost labrinth lookups
+    if !options.validate {
+        if let Err(retry_after) = state.rate_limit_queue.add(view.ip, &view.site_path).await {
+            return reject_view(
+                &req,
+                metrics,
+                ViewOutcome::RateLimited,
+                ApiError::RateLimited { retry_after },
+            );
+        }
+    }
 
-                let client = reqwest::Client::new();
+    // Lets operators shed labrinth load for a share of views, which are then recorded unattributed
+    let skip_attribution =
+        feature_flags.is_enabled("skip_project_attribution", 0, view.id.as_u64_pair().0);
 
-                let response = client
-                    .get(format!(
-                        "{}project/{}/check",
-                        dotenvy::var("LABRINTH_API_URL")?,
-                        &segments_vec[1]
-                    ))
-                    .header("x-ratelimit-key", dotenvy::var("LABRINTH_RATE_LIMIT_KEY")?)
-                    .send()
-                    .await?;
+    // Attribution is optional, so views are recorded unattributed if labrinth isn't configured
+    let attribute = !skip_attribution && state.config.labrinth_api_url.is_some();
 
-                if response.status().is_success() {
-                    let check_response = response.json::<CheckResponse>().await?;
+    // Servers that already know the viewed project pass its ID, which skips resolving the path
+    let explicit_project = url_input
+        .project_id
+        .as_deref()
+        .filter(|_| from_server)
+        .and_then(|x| Some((x, parse_base62(x).ok()?)));
 
-                    view.project_id = parse_base62(&check_response.id).unwrap_or_default();
-                }
+    let segments_vec = url
+        .path_segments()
+        .map(|x| x.collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    // The project ID or slug the view's project is looked up by in labrinth
+    let project = match explicit_project {
+        Some((project, project_id)) => {
+            view.project_id = project_id;
+            Some(project)
+        }
+        None => project_slug(&segments_vec),
+    };
+
+    if attribute && ((view.project_id == 0 && project.is_some()) || token.is_some()) {
+        // When labrinth is slow, only wait briefly for capacity before recording the view
+        // without attribution, so requests don't all pile up on labrinth
+        if let Some(_permit) = state.labrinth_limiter.acquire().await {
+            // A failed lookup leaves the view unattributed rather than losing it
+            if let Some(project) = project.filter(|_| view.project_id == 0) {
+                view.project_id = project_teams
+                    .project_id(project)
+                    .await
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default();
             }
+
+            if let Some(token) = &token {
+                view.user_id = state.user_ids.resolve(token).await.unwrap_or_default();
+            }
+
+            // Views by the project's own team are flagged so they can be left out of its
+            // analytics
+            if let Some(project) = project.filter(|_| view.user_id != 0 && view.project_id != 0) {
+                view.owner = project_teams
+                    .is_member(project, view.project_id, view.user_id)
+                    .await
+                    .unwrap_or_default();
+            }
+        } else {
+            metrics.record_labrinth_saturated();
         }
     }
 
-    analytics_queue.add_view(view).await;
+    // The view still counts towards payouts, but nothing identifying the viewer is kept. The IP was
+    // only needed for rate limiting, and the user ID for flagging team views.
+    if view.respected_dnt {
+        strip_identifying(&mut view);
+    }
 
-    Ok(HttpResponse::NoContent().body(""))
+    if options.validate {
+        // The resolved IP is used for geolocation only and never echoed back
+        view.ip = Ipv6Addr::UNSPECIFIED;
+
+        return Ok(HttpResponse::Ok().json(view));
+    }
+
+    events.publish("views", &view);
+    state.analytics_queue.add_view(view).await;
+    metrics.record_ingested("views");
+
+    metrics.record_view_outcome(ViewOutcome::Counted);
+
+    Ok(report_outcome(
+        &req,
+        HttpResponse::NoContent().body(""),
+        ViewOutcome::Counted,
+    ))
+}
+
+/// Reports the outcome of a page view in `OUTCOME_HEADER` if the request asked for it with
+/// `DEBUG_HEADER`
+fn report_outcome(
+    req: &HttpRequest,
+    mut response: HttpResponse,
+    outcome: ViewOutcome,
+) -> HttpResponse {
+    if req.headers().contains_key(DEBUG_HEADER) {
+        response.headers_mut().insert(
+            HeaderName::from_static(OUTCOME_HEADER),
+            HeaderValue::from_static(outcome.as_str()),
+        );
+    }
+
+    response
+}
+
+/// Records why a page view wasn't counted. When the request asked for the outcome, the error is
+/// rendered here so its response can carry `OUTCOME_HEADER`.
+fn reject_view(
+    req: &HttpRequest,
+    metrics: &Metrics,
+    outcome: ViewOutcome,
+    error: ApiError,
+) -> Result<HttpResponse, ApiError> {
+    metrics.record_view_outcome(outcome);
+
+    if req.headers().contains_key(DEBUG_HEADER) {
+        Ok(report_outcome(req, error.error_response(), outcome))
+    } else {
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::tests::config_from;
+    use crate::util::guards::{set_admin_keys, ADMIN_KEY_HEADER};
+    use crate::util::state::tests::app_state;
+    use actix_web::http::StatusCode;
+    use actix_web::App;
+    use serde_json::Value;
+    use std::path::Path;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Posts `body` as JSON to the download routes with the admin key, configured like `main`
+    async fn post_downloads(
+        state: &web::Data<AppState>,
+        uri: &str,
+        body: impl Into<actix_web::web::Bytes>,
+    ) -> (StatusCode, Value) {
+        set_admin_keys(vec!["key".to_string()]);
+        let config = &state.config;
+        let metrics = state.metrics.clone();
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::JsonConfig::default().error_handler(crate::routes::extractor_error))
+                .app_data(state.clone())
+                .app_data(web::Data::new(Arc::new(MaxMindIndexer::from_file(
+                    Path::new("/nonexistent"),
+                ))))
+                .app_data(web::Data::new(Arc::new(DownloadThrottle::new(None))))
+                .app_data(web::Data::new(Arc::new(DownloadDedup::new(
+                    config.rate_limit_pepper.clone(),
+                    None,
+                ))))
+                .app_data(web::Data::new(Arc::new(PrefetchDetector::new(config))))
+                .app_data(web::Data::new(Arc::new(SitePaths::new(config))))
+                .app_data(web::Data::new(Arc::new(StorageCircuit::new(1, 1000))))
+                .app_data(web::Data::new(Arc::new(
+                    EventPublisher::new(config, metrics).await,
+                )))
+                .service(downloads_ingest)
+                .service(bulk_downloads_ingest),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::post()
+            .uri(uri)
+            .insert_header((ADMIN_KEY_HEADER, "key"))
+            .insert_header(("content-type", "application/json"))
+            .set_payload(body)
+            .to_request();
+
+        let response = actix_web::test::call_service(&app, req).await;
+        let status = response.status();
+        let body = actix_web::test::read_body(response).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn view() -> PageView {
+        PageView {
+            id: Uuid::new_v4(),
+            recorded: time::record_now(),
+            domain: "modrinth.com".to_string(),
+            site_path: "/mod/sodium".to_string(),
+            full_path: String::new(),
+            referrer_domain: "google.com".to_string(),
+            from_server: false,
+            user_id: 1,
+            project_id: 2,
+            ip: Ipv4Addr::new(1, 1, 1, 1).to_ipv6_mapped(),
+            country: "US".to_string(),
+            city: Some("Seattle".to_string()),
+            asn: 13335,
+            asn_org: "Cloudflare".to_string(),
+            user_agent: "Mozilla/5.0".to_string(),
+            user_agent_kind: "browser".to_string(),
+            headers: vec![("accept-language".to_string(), "en".to_string())],
+            sample_weight: 1.0,
+            owner: true,
+            respected_dnt: true,
+        }
+    }
+
+    #[test]
+    fn keeps_only_referrer_host() {
+        assert_eq!(
+            referrer_domain(&headers(&[(
+                "referer",
+                "https://www.google.com/search?q=sodium"
+            )])),
+            "www.google.com"
+        );
+        assert_eq!(
+            referrer_domain(&headers(&[("referrer", "https://modrinth.com/mods")])),
+            "modrinth.com"
+        );
+        assert_eq!(referrer_domain(&headers(&[("referer", "not a url")])), "");
+        assert_eq!(referrer_domain(&headers(&[])), "");
+    }
+
+    #[test]
+    fn reports_outcome_when_asked() {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((DEBUG_HEADER, "1"))
+            .to_http_request();
+        let response = report_outcome(
+            &req,
+            ApiError::StorageUnavailable.error_response(),
+            ViewOutcome::StorageUnavailable,
+        );
+
+        assert_eq!(response.status().as_u16(), 503);
+        assert_eq!(
+            response.headers().get(OUTCOME_HEADER).unwrap(),
+            "storage_unavailable"
+        );
+
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let response = report_outcome(
+            &req,
+            HttpResponse::NoContent().body(""),
+            ViewOutcome::Counted,
+        );
+
+        assert!(response.headers().get(OUTCOME_HEADER).is_none());
+    }
+
+    #[test]
+    fn detects_dnt() {
+        assert!(requests_dnt(&headers(&[("dnt", "1")])));
+        assert!(requests_dnt(&headers(&[("sec-gpc", " 1 ")])));
+        assert!(!requests_dnt(&headers(&[("dnt", "0")])));
+        assert!(!requests_dnt(&headers(&[("user-agent", "Mozilla/5.0")])));
+    }
+
+    #[test]
+    fn strips_identifying_fields() {
+        let mut view = view();
+        strip_identifying(&mut view);
+
+        assert_eq!(view.ip, Ipv6Addr::UNSPECIFIED);
+        assert!(view.country.is_empty());
+        assert_eq!(view.city, None);
+        assert_eq!(view.asn, 0);
+        assert!(view.asn_org.is_empty());
+        assert!(view.user_agent.is_empty());
+        assert!(view.headers.is_empty());
+        assert_eq!(view.user_id, 0);
+        assert!(view.referrer_domain.is_empty());
+
+        // Still needed for payouts and to leave out the team's own views
+        assert_eq!(view.project_id, 2);
+        assert!(view.owner);
+    }
+
+    #[actix_rt::test]
+    async fn rejects_malformed_json() {
+        let state = web::Data::new(app_state(config_from(&[]).0));
+
+        let (status, body) = post_downloads(&state, "/v1/download", "{\"url\": ").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_input");
+        assert!(body["description"].is_string());
+        assert_eq!(state.analytics_queue.downloads_len(), 0);
+    }
+
+    /// A download of `version` of sodium, by the holder of `token` if any
+    fn batch_entry(url: &str, version: &str, token: Option<&str>) -> Value {
+        let headers = match token {
+            Some(token) => json!({ "authorization": token }),
+            None => json!({}),
+        };
+
+        json!({
+            "ip": "1.1.1.1",
+            "url": url,
+            "project_id": "sodium",
+            "version_id": version,
+            "headers": headers,
+        })
+    }
+
+    /// The downloads in the state's queue, which is emptied
+    fn queued_downloads(state: &AppState) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!("ariadne-queue-{}.json", Uuid::new_v4()));
+        state.analytics_queue.persist(&path).unwrap();
+        let queue: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        queue["downloads"].as_array().unwrap().clone()
+    }
+
+    #[actix_rt::test]
+    async fn ingests_batches() {
+        let state = web::Data::new(app_state(config_from(&[]).0));
+        let batch = json!([
+            batch_entry("https://cdn.modrinth.com/data/a.jar", "v1", None),
+            batch_entry("https://cdn.modrinth.com/data/b.jar", "v2", None),
+            batch_entry("https://cdn.modrinth.com/data/c.jar", "v3", None),
+        ]);
+
+        let (status, body) = post_downloads(&state, "/v1/downloads", batch.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "accepted": 3, "rejected": [] }));
+        assert_eq!(state.analytics_queue.downloads_len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn rejects_oversized_batches() {
+        let state = web::Data::new(app_state(
+            config_from(&[("DOWNLOAD_BATCH_MAX_SIZE", "2")]).0,
+        ));
+        let batch = json!([
+            batch_entry("https://cdn.modrinth.com/data/a.jar", "v1", None),
+            batch_entry("https://cdn.modrinth.com/data/b.jar", "v2", None),
+            batch_entry("https://cdn.modrinth.com/data/c.jar", "v3", None),
+        ]);
+
+        let (status, body) = post_downloads(&state, "/v1/downloads", batch.to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_input");
+        assert_eq!(state.analytics_queue.downloads_len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn skips_invalid_entries() {
+        let state = web::Data::new(app_state(config_from(&[]).0));
+        let batch = json!([
+            batch_entry("https://cdn.modrinth.com/data/a.jar", "v1", None),
+            batch_entry("not a url", "v2", None),
+            batch_entry("https://cdn.modrinth.com/data/c.jar", "v3", None),
+        ]);
+
+        let (status, body) = post_downloads(&state, "/v1/downloads", batch.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["accepted"], 2);
+        assert_eq!(body["rejected"].as_array().unwrap().len(), 1);
+        assert_eq!(body["rejected"][0]["index"], 1);
+        assert!(body["rejected"][0]["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid download URL"));
+        assert_eq!(state.analytics_queue.downloads_len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn looks_up_batches_once_per_token_and_project() {
+        // Dropping the server checks each lookup was made exactly once
+        let labrinth = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "id": "alice", "role": "developer" })),
+            )
+            .expect(1)
+            .mount(&labrinth)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/project/sodium/members"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "members": [{
+                    "user": { "id": "alice", "role": "developer" },
+                    "permissions": 0,
+                    "accepted": true,
+                }],
+            })))
+            .expect(1)
+            .mount(&labrinth)
+            .await;
+        for version in ["v1", "v2"] {
+            Mock::given(method("GET"))
+                .and(path(format!("/version/{version}")))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(json!({ "project_id": "sodium" })),
+                )
+                .expect(1)
+                .mount(&labrinth)
+                .await;
+        }
+
+        let state = web::Data::new(app_state(
+            config_from(&[
+                ("LABRINTH_API_URL", &format!("{}/", labrinth.uri())),
+                ("LABRINTH_RATE_LIMIT_KEY", "key"),
+                ("VERIFY_DOWNLOAD_OWNERSHIP", "true"),
+                ("DOWNLOAD_BATCH_CONCURRENCY", "2"),
+            ])
+            .0,
+        ));
+        let batch = json!([
+            batch_entry("https://cdn.modrinth.com/data/a.jar", "v1", Some("alice")),
+            batch_entry("https://cdn.modrinth.com/data/b.jar", "v2", Some("alice")),
+            batch_entry("https://cdn.modrinth.com/data/c.jar", "v1", Some("alice")),
+            batch_entry("https://cdn.modrinth.com/data/d.jar", "v2", Some("alice")),
+        ]);
+
+        let (status, body) = post_downloads(&state, "/v1/downloads", batch.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "accepted": 4, "rejected": [] }));
+
+        let downloads = queued_downloads(&state);
+        assert_eq!(downloads.len(), 4);
+        let alice = parse_base62("alice").unwrap();
+        for download in downloads {
+            assert_eq!(download["user_id"], alice);
+            assert_eq!(download["owner"], true);
+        }
+    }
 }
@@ -0,0 +1,14 @@
+use crate::routes::ApiError;
+use crate::util::state::AppState;
+use actix_web::{get, web, HttpResponse};
+
+#[get("metrics")]
+pub async fn metrics_get(state: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let metrics = &state.metrics;
+    metrics.set_labrinth_saturation(state.labrinth_limiter.saturation());
+    metrics.set_queue_state(state.analytics_queue.len(), state.analytics_queue.dropped());
+
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics.encode()?))
+}
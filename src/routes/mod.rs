@@ -1,7 +1,10 @@
+use crate::util::request_id;
 use serde::{Deserialize, Serialize};
 
+pub mod admin;
 pub mod index;
 pub mod ingest;
+pub mod metrics;
 pub mod query;
 
 #[derive(thiserror::Error, Debug)]
@@ -12,12 +15,37 @@ pub enum ApiError {
     InvalidInput(String),
     #[error("Deserialization error: {0}")]
     Json(#[from] serde_json::Error),
-    #[error("Error while communicating to labrinth")]
+    #[error("{}", labrinth_error(.0))]
     Api(#[from] reqwest::Error),
     #[error("Invalid Authentication Credentials: {0}")]
     Authentication(String),
     #[error("Clickhouse error: {0}")]
     Clickhouse(#[from] clickhouse::error::Error),
+    #[error("Metrics error: {0}")]
+    Metrics(#[from] prometheus::Error),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Too many requests, try again in {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+    #[error("Analytics storage is unavailable, try again later")]
+    StorageUnavailable,
+}
+
+/// Reports bodies and query strings actix couldn't deserialize like any other invalid input, so
+/// every client error has the same shape
+pub fn extractor_error(
+    error: impl std::fmt::Display,
+    _req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    ApiError::InvalidInput(error.to_string()).into()
+}
+
+fn labrinth_error(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        "Timed out while communicating to labrinth"
+    } else {
+        "Error while communicating to labrinth"
+    }
 }
 
 impl actix_web::ResponseError for ApiError {
@@ -26,14 +54,25 @@ impl actix_web::ResponseError for ApiError {
             ApiError::Env(..) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             ApiError::InvalidInput(..) => actix_web::http::StatusCode::BAD_REQUEST,
             ApiError::Json(..) => actix_web::http::StatusCode::BAD_REQUEST,
+            ApiError::Api(e) if e.is_timeout() => actix_web::http::StatusCode::GATEWAY_TIMEOUT,
             ApiError::Api(..) => actix_web::http::StatusCode::FAILED_DEPENDENCY,
             ApiError::Authentication(..) => actix_web::http::StatusCode::UNAUTHORIZED,
             ApiError::Clickhouse(..) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Metrics(..) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Csv(..) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::RateLimited { .. } => actix_web::http::StatusCode::TOO_MANY_REQUESTS,
+            ApiError::StorageUnavailable => actix_web::http::StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse {
-        actix_web::HttpResponse::build(self.status_code()).json(RawError {
+        let mut response = actix_web::HttpResponse::build(self.status_code());
+
+        if let ApiError::RateLimited { retry_after } = self {
+            response.insert_header((actix_web::http::header::RETRY_AFTER, *retry_after));
+        }
+
+        response.json(RawError {
             error: match self {
                 ApiError::Env(..) => "environment_error",
                 ApiError::InvalidInput(..) => "invalid_input",
@@ -41,8 +80,13 @@ impl actix_web::ResponseError for ApiError {
                 ApiError::Api(..) => "api_error",
                 ApiError::Authentication(..) => "authentication_error",
                 ApiError::Clickhouse(..) => "clickhouse_error",
+                ApiError::Metrics(..) => "metrics_error",
+                ApiError::Csv(..) => "csv_error",
+                ApiError::RateLimited { .. } => "ratelimit_error",
+                ApiError::StorageUnavailable => "storage_error",
             },
             description: &self.to_string(),
+            request_id: request_id::current(),
         })
     }
 }
@@ -51,4 +95,44 @@ impl actix_web::ResponseError for ApiError {
 pub struct RawError<'a> {
     pub error: &'a str,
     pub description: &'a str,
+    // Matches the `x-request-id` header, to find the request in the logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<uuid::Uuid>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse};
+    use serde_json::Value;
+
+    #[actix_rt::test]
+    async fn error_bodies_match_the_request_id() {
+        let app =
+            actix_web::test::init_service(App::new().wrap_fn(request_id::with_request_id).route(
+                "/fail",
+                web::get().to(|| async { Err::<HttpResponse, _>(ApiError::StorageUnavailable) }),
+            ))
+            .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/fail")
+            .to_request();
+
+        let response = actix_web::test::call_service(&app, req).await;
+        assert_eq!(
+            response.status(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+        let header = response
+            .headers()
+            .get(request_id::REQUEST_ID_HEADER)
+            .expect("missing request ID header")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let body: Value = actix_web::test::read_body_json(response).await;
+        assert_eq!(body["error"], "storage_error");
+        assert_eq!(body["request_id"], header);
+    }
 }
@@ -1,57 +1,1861 @@
 use crate::routes::ApiError;
-use actix_web::{get, web, HttpResponse};
+use actix_web::{get, web, HttpRequest, HttpResponse};
 use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use crate::models::multiplier_audits::MultiplierAudit;
+use crate::util::auth::Authorizer;
+use crate::util::base62::parse_base62;
+use crate::util::cache::TtlCache;
+use crate::util::config::Config;
+use crate::util::flags::FeatureFlags;
 use crate::util::guards::admin_key_guard;
+use crate::util::ip::PRIVATE_COUNTRY;
+use crate::util::state::AppState;
+use crate::util::stream::{
+    csv_download, csv_response, csv_stream, json_stream, ndjson_stream, Format,
+};
+use crate::util::time;
+use clickhouse::query::RowCursor;
 use clickhouse::Row;
-use serde::Deserialize;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use uuid::Uuid;
+
+const MAX_MULTIPLIERS_LIMIT: u64 = 10_000;
+
+// Page views in a group of `views` rows. Each row is weighted by how many views it stands for, so
+// sampled projects aren't underrepresented next to unsampled ones.
+const WEIGHTED_VIEWS: &str = "toUInt64(round(sum(sample_weight)))";
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl Order {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct MultipliersQuery {
     start_date: DateTime<Utc>,
+    // Exclusive, and at most `MAX_MULTIPLIER_RANGE_DAYS` after the start. The multipliers of the
+    // day starting at `start_date` are computed without it.
+    end_date: Option<DateTime<Utc>>,
+    // Pages through the values when set, clamped to `MAX_MULTIPLIERS_LIMIT`. Without it every
+    // value is returned, as payouts need all of them.
+    limit: Option<u64>,
+    #[serde(default)]
+    offset: u64,
+    // Order of the values by page views
+    #[serde(default)]
+    order: Order,
+    format: Option<Format>,
+}
+
+impl MultipliersQuery {
+    /// The `(limit, offset)` of the requested page, or `None` for every value
+    fn page(&self) -> Option<(u64, u64)> {
+        self.limit
+            .map(|limit| (limit.min(MAX_MULTIPLIERS_LIMIT), self.offset))
+    }
+
+    /// The `[start, end)` range to aggregate over: the day (UTC) containing `start_date` unless an
+    /// `end_date` is given
+    fn range(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), ApiError> {
+        let start = self.start_date.date().and_hms(0, 0, 0);
+        let end = self.end_date.unwrap_or(start + Duration::days(1));
+
+        check_range(start, end)?;
+        if end - start > Duration::days(MAX_MULTIPLIER_RANGE_DAYS) {
+            return Err(ApiError::InvalidInput(format!(
+                "the range must span at most {MAX_MULTIPLIER_RANGE_DAYS} days!"
+            )));
+        }
+
+        Ok((start, end))
+    }
+}
+
+#[derive(Serialize, Deserialize, Row)]
+struct ProjectMultiplier {
+    pub page_views: u64,
+    pub project_id: u64,
+}
+
+async fn multiplier_sum(
+    client: &clickhouse::Client,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<u64, clickhouse::error::Error> {
+    client
+        .query(&format!(
+            "SELECT {WEIGHTED_VIEWS} FROM views WHERE recorded BETWEEN ? AND ?"
+        ))
+        .bind(time::to_query_ts(start))
+        .bind(time::to_query_ts(end))
+        .fetch_one::<u64>()
+        .await
+}
+
+/// Streams the multipliers of every project viewed in the range, or the page of `(limit, offset)`
+fn multiplier_values(
+    client: &clickhouse::Client,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    order: Order,
+    page: Option<(u64, u64)>,
+) -> Result<RowCursor<ProjectMultiplier>, clickhouse::error::Error> {
+    // Ties are broken by project ID, so pages don't overlap
+    let query = client
+        .query(&format!(
+            r#"
+            SELECT {WEIGHTED_VIEWS} page_views, project_id
+            FROM views
+            WHERE recorded BETWEEN ? AND ?
+            GROUP BY project_id
+            ORDER BY page_views {order}, project_id
+            {}
+            "#,
+            if page.is_some() {
+                "LIMIT ? OFFSET ?"
+            } else {
+                ""
+            },
+            order = order.as_sql(),
+        ))
+        .bind(time::to_query_ts(start))
+        .bind(time::to_query_ts(end));
+
+    match page {
+        Some((limit, offset)) => query.bind(limit).bind(offset),
+        None => query,
+    }
+    .fetch::<ProjectMultiplier>()
 }
 
-/// Internal route - retrieves payout multipliers for each day
+/// Computes the multipliers between `start` and `end` as `{"sum": .., "values": {..}}`,
+/// collecting every value. When `AUDIT_MULTIPLIERS` is set, the computation is recorded in the
+/// `multiplier_audits` table.
+async fn collect_multipliers(
+    client: &clickhouse::Client,
+    config: &Config,
+    feature_flags: &FeatureFlags,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<serde_json::Value, ApiError> {
+    let sum = multiplier_sum(client, start, end).await?;
+
+    let mut cursor = multiplier_values(client, start, end, Order::Desc, None)?;
+    let mut values = Vec::new();
+    while let Some(row) = cursor.next().await? {
+        values.push((row.project_id, row.page_views));
+    }
+
+    let response = json!({
+        "sum": sum,
+        "values": values
+            .iter()
+            .map(|(project_id, page_views)| (project_id.to_string(), json!(page_views)))
+            .collect::<serde_json::Map<_, _>>(),
+    });
+
+    if config.audit_multipliers {
+        let audit = MultiplierAudit {
+            id: Uuid::new_v4(),
+            recorded: time::record_now(),
+            start: time::to_record_ts(start),
+            end: time::to_record_ts(end),
+            sum,
+            values,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            config: json!({
+                "weighting": "sample_weight",
+                "feature_flags": feature_flags.to_json(),
+            })
+            .to_string(),
+        };
+
+        let mut insert = client.insert("multiplier_audits")?;
+        insert.write(&audit).await?;
+        insert.end().await?;
+    }
+
+    Ok(response)
+}
+
+/// Internal route - retrieves payout multipliers for each day, or aggregated over a range up to
+/// `end_date`. With a `limit`, only a page of the values is returned, along with the `total`
+/// number of projects to page through. As CSV, only the values are returned.
 #[get("v1/multipliers", guard = "admin_key_guard")]
 pub async fn multipliers_query(
+    req: HttpRequest,
     web::Query(query): web::Query<MultipliersQuery>,
     client: web::Data<clickhouse::Client>,
+    state: web::Data<AppState>,
+    feature_flags: web::Data<Arc<FeatureFlags>>,
+) -> Result<HttpResponse, ApiError> {
+    let config = &state.config;
+    let (start, end) = query.range()?;
+    let page = query.page();
+
+    // Exports are for analysis rather than payouts, so they aren't audited either
+    if Format::of(&req, query.format) == Format::Csv {
+        let values = multiplier_values(&client, start, end, query.order, page)?;
+
+        let filename = match query.end_date {
+            Some(end) => format!(
+                "multipliers-{}-{}.csv",
+                start.format("%Y-%m-%d"),
+                end.format("%Y-%m-%d")
+            ),
+            None => format!("multipliers-{}.csv", start.format("%Y-%m-%d")),
+        };
+
+        return Ok(csv_download(&filename).streaming(csv_stream(values)));
+    }
+
+    // An audit has to hold every value, so audited computations are collected before responding.
+    // Pages aren't full computations, so they aren't audited.
+    if page.is_none() && config.audit_multipliers {
+        return Ok(HttpResponse::Ok()
+            .json(collect_multipliers(&client, config, &feature_flags, start, end).await?));
+    }
+
+    let sum = multiplier_sum(&client, start, end).await?;
+
+    let prefix = match page {
+        Some(_) => {
+            let total = client
+                .query("SELECT uniqExact(project_id) FROM views WHERE recorded BETWEEN ? AND ?")
+                .bind(time::to_query_ts(start))
+                .bind(time::to_query_ts(end))
+                .fetch_one::<u64>()
+                .await?;
+
+            format!(r#"{{"sum":{sum},"total":{total},"values":{{"#)
+        }
+        None => format!(r#"{{"sum":{sum},"values":{{"#),
+    };
+
+    // The values span every viewed project, so they are streamed rather than collected
+    let values = multiplier_values(&client, start, end, query.order, page)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .streaming(json_stream(prefix, values, "}}", |x, buf| {
+            serde_json::to_writer(&mut *buf, &x.project_id.to_string())?;
+            buf.push(b':');
+            serde_json::to_writer(buf, &x.page_views)
+        })))
+}
+
+#[derive(Deserialize)]
+pub struct ProjectMultiplierQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+}
+
+/// Retrieves a project's payout multiplier for the day (UTC) containing `start_date`, alongside
+/// the day's sum over every project. Unlike `v1/multipliers`, this is authorized with the
+/// requester's token and scoped to a project they may view, so creators can read their own.
+#[get("v1/project_multiplier")]
+pub async fn project_multiplier_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<ProjectMultiplierQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
 ) -> Result<HttpResponse, ApiError> {
     let start = query.start_date.date().and_hms(0, 0, 0);
     let end = start + Duration::days(1);
 
+    let project_id =
+        authorize_project_range(&req, &authorizer, &query.project_id, start, end, false).await?;
+
+    let sum = multiplier_sum(&client, start, end).await?;
+    let page_views = client
+        .query(&format!(
+            r#"
+            SELECT {WEIGHTED_VIEWS}
+            FROM views
+            WHERE project_id = ? AND recorded BETWEEN ? AND ?
+            "#
+        ))
+        .bind(project_id)
+        .bind(time::to_query_ts(start))
+        .bind(time::to_query_ts(end))
+        .fetch_one::<u64>()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "sum": sum,
+        "value": page_views,
+    })))
+}
+
+const MAX_MULTIPLIER_RANGE_DAYS: i64 = 92;
+// How many days' multipliers are computed at once
+const MULTIPLIER_RANGE_CONCURRENCY: usize = 4;
+
+#[derive(Deserialize)]
+pub struct MultipliersRangeQuery {
+    start_date: DateTime<Utc>,
+    // Exclusive, and at most `MAX_MULTIPLIER_RANGE_DAYS` after `start_date`
+    end_date: DateTime<Utc>,
+}
+
+/// Internal route - retrieves the payout multipliers of every day (UTC) in a range at once, as a
+/// map of `YYYY-MM-DD` to the same `{"sum", "values"}` the single-day route returns. Days are
+/// computed a few at a time, and unlike the single-day route every value is collected before
+/// responding.
+#[get("v1/multipliers_range", guard = "admin_key_guard")]
+pub async fn multipliers_range_query(
+    web::Query(query): web::Query<MultipliersRangeQuery>,
+    client: web::Data<clickhouse::Client>,
+    state: web::Data<AppState>,
+    feature_flags: web::Data<Arc<FeatureFlags>>,
+) -> Result<HttpResponse, ApiError> {
+    let config = &state.config;
+    let start = query.start_date.date().and_hms(0, 0, 0);
+    let days = (query.end_date - start).num_days();
+
+    if !(1..=MAX_MULTIPLIER_RANGE_DAYS).contains(&days) {
+        return Err(ApiError::InvalidInput(format!(
+            "the range must span between 1 and {MAX_MULTIPLIER_RANGE_DAYS} days!"
+        )));
+    }
+
+    let multipliers = futures::stream::iter(0..days)
+        .map(|day| {
+            let day = start + Duration::days(day);
+            let client = &client;
+            let config = &config;
+            let feature_flags = &feature_flags;
+
+            async move {
+                let multipliers = collect_multipliers(
+                    client,
+                    config,
+                    feature_flags,
+                    day,
+                    day + Duration::days(1),
+                )
+                .await?;
+                Ok::<_, ApiError>((day.format("%Y-%m-%d").to_string(), multipliers))
+            }
+        })
+        .buffered(MULTIPLIER_RANGE_CONCURRENCY)
+        .try_collect::<serde_json::Map<_, _>>()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(multipliers))
+}
+
+/// Rejects ranges that end before or when they start
+fn check_range(start_date: DateTime<Utc>, end_date: DateTime<Utc>) -> Result<(), ApiError> {
+    if end_date <= start_date {
+        return Err(ApiError::InvalidInput(
+            "end date must be after start date!".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks the requester may view the project's analytics (or payouts, with
+/// `use_payouts_permission`) and that the range is well-formed, returning the parsed project ID
+async fn authorize_project_range(
+    req: &HttpRequest,
+    authorizer: &Authorizer,
+    project_id: &str,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    use_payouts_permission: bool,
+) -> Result<u64, ApiError> {
+    authorizer
+        .check(Some(project_id), req.headers(), use_payouts_permission)
+        .await?;
+
+    let project_id = parse_base62(project_id)
+        .map_err(|_| ApiError::InvalidInput("invalid project ID specified!".to_string()))?;
+
+    check_range(start_date, end_date)?;
+
+    Ok(project_id)
+}
+
+#[derive(Deserialize)]
+pub struct DaysOfWeekQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    // IANA timezone name days are computed in, defaults to UTC
+    timezone: Option<String>,
+    // Include traffic from the project's own team, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+}
+
+/// Parses an IANA timezone name, defaulting to UTC
+fn parse_timezone(timezone: Option<&str>) -> Result<Tz, ApiError> {
+    timezone
+        .map_or(Ok(Tz::UTC), str::parse)
+        .map_err(|_| ApiError::InvalidInput("invalid timezone specified!".to_string()))
+}
+
+/// Retrieves a project's downloads and page views grouped by day of the week (Monday first)
+#[get("v1/days_of_week")]
+pub async fn days_of_week_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<DaysOfWeekQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        false,
+    )
+    .await?;
+
+    // Checked here, as ClickHouse would only fail the whole query with a server error
+    let timezone = parse_timezone(query.timezone.as_deref())?;
+
+    #[derive(Deserialize, Row)]
+    struct DayOfWeekCount {
+        // 1 (Monday) through 7 (Sunday)
+        pub day: u8,
+        pub count: u64,
+    }
+
+    let day_of_week_query = |table: &str, count: &str, filter: &str| {
+        client
+            .query(&format!(
+                r#"
+                SELECT toDayOfWeek(toTimeZone(recorded, ?)) day, {count} count
+                FROM {table}
+                WHERE project_id = ? AND recorded BETWEEN ? AND ? AND (? OR NOT owner) {filter}
+                GROUP BY day
+                "#
+            ))
+            .bind(timezone.name())
+            .bind(project_id)
+            .bind(time::to_query_ts(query.start_date))
+            .bind(time::to_query_ts(query.end_date))
+            .bind(query.include_owner)
+            .fetch_all::<DayOfWeekCount>()
+    };
+
+    let (downloads, views) = futures::future::try_join(
+        day_of_week_query("downloads", "COUNT(id)", "AND counted"),
+        day_of_week_query("views", WEIGHTED_VIEWS, ""),
+    )
+    .await?;
+
+    // ClickHouse doesn't guarantee any ordering of the groups, so place them by index
+    let mut days = [(0u64, 0u64); 7];
+    for row in downloads {
+        if let Some(day) = days.get_mut(row.day.wrapping_sub(1) as usize) {
+            day.0 = row.count;
+        }
+    }
+    for row in views {
+        if let Some(day) = days.get_mut(row.day.wrapping_sub(1) as usize) {
+            day.1 = row.count;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "days": days
+            .iter()
+            .enumerate()
+            .map(|(day, (downloads, views))| json!({
+                "day": day,
+                "downloads": downloads,
+                "views": views,
+            }))
+            .collect::<Vec<_>>()
+    })))
+}
+
+const DEFAULT_LOOKBACK_DAYS: i64 = 30;
+const MAX_LOOKBACK_DAYS: i64 = 365;
+
+#[derive(Deserialize)]
+pub struct VisitorsQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    // How many days before `start_date` to search for earlier visits
+    lookback_days: Option<i64>,
+    // Include traffic from the project's own team, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+}
+
+/// Splits a project's visitors in a range into new and returning visitors.
+///
+/// Visitors are identified by a hash of their IP and user agent, so views that respected DNT, which
+/// store neither, are left out. Under sampling, each visitor seen stands for the average sample
+/// weight of their views, which overcounts visitors with several views. A visitor is "returning" if
+/// they viewed the project within `lookback_days` (default 30) before `start_date`, and "new"
+/// otherwise. Visits older than the lookback window are not considered, so a visitor whose last
+/// visit was further back is counted as new - widen the lookback for long-term accuracy at the cost
+/// of scanning more history.
+#[get("v1/visitors")]
+pub async fn visitors_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<VisitorsQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        false,
+    )
+    .await?;
+
+    let lookback_days = query.lookback_days.unwrap_or(DEFAULT_LOOKBACK_DAYS);
+    if !(0..=MAX_LOOKBACK_DAYS).contains(&lookback_days) {
+        return Err(ApiError::InvalidInput(format!(
+            "lookback must be between 0 and {MAX_LOOKBACK_DAYS} days!"
+        )));
+    }
+    let lookback_start = query.start_date - Duration::days(lookback_days);
+
+    #[derive(Deserialize, Row)]
+    struct VisitorSplit {
+        pub new_visitors: u64,
+        pub returning_visitors: u64,
+    }
+
+    let split = client
+        .query(
+            r#"
+            SELECT
+                toUInt64(round(sumIf(weight, first_seen >= ?))) new_visitors,
+                toUInt64(round(sumIf(weight, first_seen < ?))) returning_visitors
+            FROM (
+                SELECT
+                    cityHash64(ip, user_agent) visitor,
+                    min(recorded) first_seen,
+                    avg(sample_weight) weight
+                FROM views
+                WHERE project_id = ? AND recorded BETWEEN ? AND ? AND (? OR NOT owner)
+                    AND NOT respected_dnt
+                GROUP BY visitor
+                HAVING max(recorded) >= ?
+            )
+            "#,
+        )
+        .bind(time::to_query_ts(query.start_date))
+        .bind(time::to_query_ts(query.start_date))
+        .bind(project_id)
+        .bind(time::to_query_ts(lookback_start))
+        .bind(time::to_query_ts(query.end_date))
+        .bind(query.include_owner)
+        .bind(time::to_query_ts(query.start_date))
+        .fetch_one::<VisitorSplit>()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "new": split.new_visitors,
+        "returning": split.returning_visitors,
+        "lookback_days": lookback_days,
+    })))
+}
+
+const DEFAULT_SUSPICIOUS_DAYS: i64 = 7;
+const MAX_SUSPICIOUS_DAYS: i64 = 30;
+// Projects with fewer recent downloads than this are too small to judge
+const SUSPICIOUS_MIN_DOWNLOADS: u64 = 100;
+const SUSPICIOUS_RESULTS: usize = 100;
+
+// Signal thresholds
+const TOP_COUNTRY_SHARE: f64 = 0.9;
+const SHORT_USER_AGENT_LENGTH: u64 = 10;
+const SHORT_USER_AGENT_SHARE: f64 = 0.5;
+const DOWNLOADS_PER_VIEW: f64 = 10.0;
+
+#[derive(Deserialize)]
+pub struct SuspiciousProjectsQuery {
+    days: Option<i64>,
+}
+
+/// Internal route - ranks projects whose recent downloads look botted. A project is flagged when
+/// its downloads are concentrated in a single country, dwarf its page views, or mostly come from
+/// empty or very short user agents. Projects are ranked by the number of signals they trigger,
+/// then by downloads.
+#[get("v1/suspicious_projects", guard = "admin_key_guard")]
+pub async fn suspicious_projects_query(
+    web::Query(query): web::Query<SuspiciousProjectsQuery>,
+    client: web::Data<clickhouse::Client>,
+) -> Result<HttpResponse, ApiError> {
+    let days = query.days.unwrap_or(DEFAULT_SUSPICIOUS_DAYS);
+    if !(1..=MAX_SUSPICIOUS_DAYS).contains(&days) {
+        return Err(ApiError::InvalidInput(format!(
+            "days must be between 1 and {MAX_SUSPICIOUS_DAYS}!"
+        )));
+    }
+    let start = Utc::now() - Duration::days(days);
+
     #[derive(Deserialize, Row)]
-    struct ProjectMultiplier {
-        pub page_views: u64,
+    struct ProjectTraffic {
         pub project_id: u64,
+        pub downloads: u64,
+        pub views: u64,
+        pub top_country_share: f64,
+        pub short_user_agent_share: f64,
+    }
+
+    let projects = client
+        .query(&format!(
+            r#"
+            SELECT project_id, downloads, views, top_country_share, short_user_agent_share
+            FROM (
+                SELECT
+                    project_id,
+                    sum(country_downloads) downloads,
+                    max(country_downloads) / sum(country_downloads) top_country_share,
+                    sum(short_user_agent_downloads) / sum(country_downloads) short_user_agent_share
+                FROM (
+                    SELECT
+                        project_id,
+                        country,
+                        count() country_downloads,
+                        countIf(length(user_agent) < ?) short_user_agent_downloads
+                    FROM downloads
+                    WHERE recorded >= ? AND project_id != 0
+                    GROUP BY project_id, country
+                )
+                GROUP BY project_id
+                HAVING downloads >= ?
+            ) d
+            LEFT JOIN (
+                SELECT project_id, {WEIGHTED_VIEWS} views
+                FROM views
+                WHERE recorded >= ? AND project_id != 0
+                GROUP BY project_id
+            ) v USING (project_id)
+            "#
+        ))
+        .bind(SHORT_USER_AGENT_LENGTH)
+        .bind(time::to_query_ts(start))
+        .bind(SUSPICIOUS_MIN_DOWNLOADS)
+        .bind(time::to_query_ts(start))
+        .fetch_all::<ProjectTraffic>()
+        .await?;
+
+    let mut flagged = projects
+        .into_iter()
+        .filter_map(|project| {
+            let mut signals = Vec::new();
+
+            if project.top_country_share >= TOP_COUNTRY_SHARE {
+                signals.push("country_concentration");
+            }
+            if project.short_user_agent_share >= SHORT_USER_AGENT_SHARE {
+                signals.push("short_user_agents");
+            }
+            if project.downloads as f64 >= project.views as f64 * DOWNLOADS_PER_VIEW {
+                signals.push("download_view_ratio");
+            }
+
+            (!signals.is_empty()).then_some((project, signals))
+        })
+        .collect::<Vec<_>>();
+
+    flagged.sort_unstable_by(|a, b| {
+        b.1.len()
+            .cmp(&a.1.len())
+            .then(b.0.downloads.cmp(&a.0.downloads))
+    });
+    flagged.truncate(SUSPICIOUS_RESULTS);
+
+    Ok(HttpResponse::Ok().json(
+        flagged
+            .into_iter()
+            .map(|(project, signals)| {
+                json!({
+                    "project_id": project.project_id,
+                    "downloads": project.downloads,
+                    "views": project.views,
+                    "top_country_share": project.top_country_share,
+                    "short_user_agent_share": project.short_user_agent_share,
+                    "signals": signals,
+                })
+            })
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// How precisely `v1/downloads_map` locates downloads
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MapGranularity {
+    #[default]
+    Country,
+    /// The country and its largest subdivision, as an ISO 3166-2 code like `US-WA`
+    Subdivision,
+}
+
+impl MapGranularity {
+    // The region a download is grouped into. Downloads without a known subdivision are grouped by
+    // their country alone, so no downloads are left out of the map.
+    fn as_sql(&self) -> &'static str {
+        match self {
+            MapGranularity::Country => "country",
+            MapGranularity::Subdivision => {
+                "if(subdivision = '', country, concat(country, '-', subdivision))"
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DownloadsMapQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    // Include traffic from the project's own team, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+    #[serde(default)]
+    granularity: MapGranularity,
+}
+
+/// Counts a project's downloads per region in the range
+async fn download_regions(
+    client: &clickhouse::Client,
+    project_id: u64,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    include_owner: bool,
+    granularity: MapGranularity,
+) -> Result<HashMap<String, u64>, clickhouse::error::Error> {
+    #[derive(Deserialize, Row)]
+    struct RegionDownloads {
+        pub region: String,
+        pub downloads: u64,
+    }
+
+    let regions = client
+        .query(&format!(
+            r#"
+            SELECT {} region, COUNT(id) downloads
+            FROM downloads
+            WHERE project_id = ? AND recorded BETWEEN ? AND ? AND country != '' AND counted
+                AND (? OR NOT owner)
+            GROUP BY region
+            "#,
+            granularity.as_sql(),
+        ))
+        .bind(project_id)
+        .bind(time::to_query_ts(start))
+        .bind(time::to_query_ts(end))
+        .bind(include_owner)
+        .fetch_all::<RegionDownloads>()
+        .await?;
+
+    Ok(regions
+        .into_iter()
+        .map(|x| (x.region, x.downloads))
+        .collect())
+}
+
+/// Retrieves a project's downloads per country, or per subdivision with
+/// `granularity=subdivision`, for map visualizations.
+///
+/// Location is deliberately coarsened here: downloads are only ever grouped by country or its
+/// largest subdivision (e.g. a US state), and only the subdivision is recorded at ingest even when
+/// the city is known, so the map can't be used to narrow down where individual downloads came
+/// from. Downloads without a known country are omitted.
+#[get("v1/downloads_map")]
+pub async fn downloads_map_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<DownloadsMapQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        false,
+    )
+    .await?;
+
+    let regions = download_regions(
+        &client,
+        project_id,
+        query.start_date,
+        query.end_date,
+        query.include_owner,
+        query.granularity,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(match query.granularity {
+        MapGranularity::Country => json!({ "countries": regions }),
+        MapGranularity::Subdivision => json!({ "subdivisions": regions }),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct DownloadCompletionQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+}
+
+/// Retrieves how many of a project's two-phase downloads were completed rather than expiring.
+/// Sessions still awaiting completion are not included.
+#[get("v1/download_completion")]
+pub async fn download_completion_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<DownloadCompletionQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        false,
+    )
+    .await?;
+
+    #[derive(Deserialize, Row)]
+    struct Completion {
+        pub started: u64,
+        pub completed: u64,
+    }
+
+    let completion = client
+        .query(
+            r#"
+            SELECT count() started, countIf(completed) completed
+            FROM download_sessions
+            WHERE project_id = ? AND recorded BETWEEN ? AND ?
+            "#,
+        )
+        .bind(project_id)
+        .bind(time::to_query_ts(query.start_date))
+        .bind(time::to_query_ts(query.end_date))
+        .fetch_one::<Completion>()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "started": completion.started,
+        "completed": completion.completed,
+        "completion_rate": if completion.started == 0 {
+            0.0
+        } else {
+            completion.completed as f64 / completion.started as f64
+        },
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct RangedDownloadsQuery {
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+}
+
+/// Internal route - summarizes ranged download requests in a range, to check they are coalesced:
+/// `counted` should be close to the number of distinct downloaders, with the rest of the requests
+/// left uncounted as continuations.
+#[get("v1/ranged_downloads", guard = "admin_key_guard")]
+pub async fn ranged_downloads_query(
+    web::Query(query): web::Query<RangedDownloadsQuery>,
+    client: web::Data<clickhouse::Client>,
+) -> Result<HttpResponse, ApiError> {
+    check_range(query.start_date, query.end_date)?;
+
+    #[derive(Deserialize, Row)]
+    struct RangedDownloads {
+        pub requests: u64,
+        pub counted: u64,
+        // Distinct (IP, version) pairs making ranged requests
+        pub downloaders: u64,
+    }
+
+    let ranged = client
+        .query(
+            r#"
+            SELECT count() requests, countIf(counted) counted, uniqExact(ip, version_id) downloaders
+            FROM downloads
+            WHERE recorded BETWEEN ? AND ? AND range != ''
+            "#,
+        )
+        .bind(time::to_query_ts(query.start_date))
+        .bind(time::to_query_ts(query.end_date))
+        .fetch_one::<RangedDownloads>()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "requests": ranged.requests,
+        "counted": ranged.counted,
+        "uncounted": ranged.requests - ranged.counted,
+        "downloaders": ranged.downloaders,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct LifetimeTotalsQuery {
+    project_id: String,
+    // Include traffic from the project's own team, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+}
+
+/// A project's counted downloads and views
+#[derive(Clone, Copy, Serialize)]
+pub struct ProjectTotals {
+    pub downloads: u64,
+    pub views: u64,
+}
+
+/// Counts a project's downloads and views, either recorded between two timestamps (in seconds) or
+/// over its whole history
+pub async fn project_totals(
+    client: &clickhouse::Client,
+    project_id: u64,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    include_owner: bool,
+) -> Result<ProjectTotals, clickhouse::error::Error> {
+    let range_filter = if range.is_some() {
+        "AND recorded BETWEEN ? AND ?"
+    } else {
+        ""
+    };
+
+    let totals_query = |sql: String| {
+        let mut query = client.query(&sql).bind(project_id);
+        if let Some((start, end)) = range {
+            query = query
+                .bind(time::to_query_ts(start))
+                .bind(time::to_query_ts(end));
+        }
+        query.bind(include_owner)
+    };
+
+    let (downloads, views) = futures::future::try_join(
+        totals_query(format!(
+            r#"
+            SELECT count()
+            FROM downloads
+            WHERE project_id = ? {range_filter} AND counted AND (? OR NOT owner)
+            "#
+        ))
+        .fetch_one::<u64>(),
+        totals_query(format!(
+            r#"
+            SELECT {WEIGHTED_VIEWS}
+            FROM views
+            WHERE project_id = ? {range_filter} AND (? OR NOT owner)
+            "#
+        ))
+        .fetch_one::<u64>(),
+    )
+    .await?;
+
+    Ok(ProjectTotals { downloads, views })
+}
+
+/// Retrieves a project's all-time download and view totals.
+///
+/// There are no downsampled summary tables to read from, so this scans the project's full raw
+/// history. Totals change slowly, so they are cached for a few minutes to keep repeat requests
+/// from rescanning it.
+#[get("v1/lifetime_totals")]
+pub async fn lifetime_totals_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<LifetimeTotalsQuery>,
+    client: web::Data<clickhouse::Client>,
+    totals_cache: web::Data<Arc<TtlCache<(u64, bool), ProjectTotals>>>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    authorizer
+        .check(Some(&query.project_id), req.headers(), false)
+        .await?;
+
+    let project_id = parse_base62(&query.project_id)
+        .map_err(|_| ApiError::InvalidInput("invalid project ID specified!".to_string()))?;
+
+    let key = (project_id, query.include_owner);
+    let totals = if let Some(totals) = totals_cache.get(&key) {
+        totals
+    } else {
+        let totals = project_totals(&client, project_id, None, query.include_owner).await?;
+        totals_cache.insert(key, totals);
+        totals
+    };
+
+    Ok(HttpResponse::Ok().json(totals))
+}
+
+#[derive(Deserialize)]
+pub struct DailyMetricsQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    // Include traffic from the project's own team, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+}
+
+/// Streams a project's counted downloads and views per day (UTC) as newline-delimited JSON, for
+/// pipelines that process the rows one at a time. Days without any downloads or views are omitted.
+#[get("v1/daily_metrics")]
+pub async fn daily_metrics_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<DailyMetricsQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        false,
+    )
+    .await?;
+
+    #[derive(Deserialize, Serialize, Row)]
+    struct DailyMetrics {
+        // YYYY-MM-DD
+        pub date: String,
+        pub downloads: u64,
+        pub views: u64,
+    }
+
+    let rows = client
+        .query(&format!(
+            r#"
+            SELECT toString(day) date, sum(downloads) downloads, sum(views) views
+            FROM (
+                SELECT toDate(recorded) day, count() downloads, toUInt64(0) views
+                FROM downloads
+                WHERE project_id = ? AND recorded BETWEEN ? AND ? AND counted
+                    AND (? OR NOT owner)
+                GROUP BY day
+                UNION ALL
+                SELECT toDate(recorded) day, toUInt64(0) downloads, {WEIGHTED_VIEWS} views
+                FROM views
+                WHERE project_id = ? AND recorded BETWEEN ? AND ? AND (? OR NOT owner)
+                GROUP BY day
+            )
+            GROUP BY day
+            ORDER BY day
+            "#
+        ))
+        .bind(project_id)
+        .bind(time::to_query_ts(query.start_date))
+        .bind(time::to_query_ts(query.end_date))
+        .bind(query.include_owner)
+        .bind(project_id)
+        .bind(time::to_query_ts(query.start_date))
+        .bind(time::to_query_ts(query.end_date))
+        .bind(query.include_owner)
+        .fetch::<DailyMetrics>()?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(ndjson_stream(rows)))
+}
+
+#[derive(Deserialize)]
+pub struct LoggedInQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    // Include traffic from the project's own team, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+}
+
+/// Splits a project's counted downloads and views into those by logged in users and anonymous
+/// ones
+#[get("v1/logged_in")]
+pub async fn logged_in_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<LoggedInQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        false,
+    )
+    .await?;
+
+    #[derive(Deserialize, Row)]
+    struct LoggedInSplit {
+        pub logged_in: u64,
+        pub anonymous: u64,
     }
 
-    let (values, sum) = futures::future::try_join(
+    let logged_in_query = |table: &str, filter: &str| {
+        client
+            .query(&format!(
+                r#"
+                SELECT countIf(user_id != 0) logged_in, countIf(user_id = 0) anonymous
+                FROM {table}
+                WHERE project_id = ? AND recorded BETWEEN ? AND ? AND (? OR NOT owner) {filter}
+                "#
+            ))
+            .bind(project_id)
+            .bind(time::to_query_ts(query.start_date))
+            .bind(time::to_query_ts(query.end_date))
+            .bind(query.include_owner)
+            .fetch_one::<LoggedInSplit>()
+    };
+
+    let (downloads, views) = futures::future::try_join(
+        logged_in_query("downloads", "AND counted"),
+        logged_in_query("views", ""),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "downloads": {
+            "logged_in": downloads.logged_in,
+            "anonymous": downloads.anonymous,
+        },
+        "views": {
+            "logged_in": views.logged_in,
+            "anonymous": views.anonymous,
+        },
+    })))
+}
+
+// Keeps a single cohort query from fanning out into too many labrinth team lookups
+const MAX_COHORT_PROJECTS: usize = 100;
+
+#[derive(Deserialize)]
+pub struct CohortQuery {
+    // JSON array of base62 project IDs, e.g. `["AABBCCDD","EEFFGGHH"]`
+    project_ids: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    // Include traffic from the projects' own teams, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+}
+
+/// Retrieves the combined downloads, views and downloads per country of a set of projects, as if
+/// they were one project. The requester must be allowed to view the analytics of every project in
+/// the set.
+#[get("v1/cohort")]
+pub async fn cohort_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<CohortQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let mut project_ids = serde_json::from_str::<Vec<String>>(&query.project_ids)
+        .map_err(|_| ApiError::InvalidInput("project IDs must be a JSON array!".to_string()))?;
+    project_ids.sort_unstable();
+    project_ids.dedup();
+
+    if project_ids.is_empty() || project_ids.len() > MAX_COHORT_PROJECTS {
+        return Err(ApiError::InvalidInput(format!(
+            "between 1 and {MAX_COHORT_PROJECTS} project IDs must be specified!"
+        )));
+    }
+
+    let ids = project_ids.iter().map(String::as_str).collect::<Vec<_>>();
+    authorizer.check_all(&ids, req.headers(), false).await?;
+
+    let ids = project_ids
+        .iter()
+        .map(|x| parse_base62(x))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| ApiError::InvalidInput("invalid project ID specified!".to_string()))?;
+
+    check_range(query.start_date, query.end_date)?;
+
+    #[derive(Deserialize, Row)]
+    struct CountryDownloads {
+        pub country: String,
+        pub downloads: u64,
+    }
+
+    let (countries, views) = futures::future::try_join(
         client
             .query(
                 r#"
-            SELECT COUNT(id) page_views, project_id
-            FROM views
-            WHERE recorded BETWEEN ? AND ?
-            GROUP BY project_id
-            ORDER BY page_views DESC
-            "#,
+                SELECT country, COUNT(id) downloads
+                FROM downloads
+                WHERE project_id IN ? AND recorded BETWEEN ? AND ? AND counted
+                    AND (? OR NOT owner)
+                GROUP BY country
+                "#,
             )
-            .bind(start.timestamp())
-            .bind(end.timestamp())
-            .fetch_all::<ProjectMultiplier>(),
+            .bind(&ids)
+            .bind(time::to_query_ts(query.start_date))
+            .bind(time::to_query_ts(query.end_date))
+            .bind(query.include_owner)
+            .fetch_all::<CountryDownloads>(),
         client
-            .query("SELECT COUNT(id) FROM views WHERE recorded BETWEEN ? AND ?")
-            .bind(start.timestamp())
-            .bind(end.timestamp())
-            .fetch_one::<i32>(),
+            .query(&format!(
+                r#"
+                SELECT {WEIGHTED_VIEWS}
+                FROM views
+                WHERE project_id IN ? AND recorded BETWEEN ? AND ? AND (? OR NOT owner)
+                "#
+            ))
+            .bind(&ids)
+            .bind(time::to_query_ts(query.start_date))
+            .bind(time::to_query_ts(query.end_date))
+            .bind(query.include_owner)
+            .fetch_one::<u64>(),
     )
     .await?;
 
-    Ok(HttpResponse::Ok().json(json! ({
-        "sum": sum,
-        "values": values.into_iter().map(|x| (x.project_id, x.page_views)).collect::<HashMap<u64, u64>>()
+    // Downloads without a known country are only part of the total
+    let downloads = countries.iter().map(|x| x.downloads).sum::<u64>();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "downloads": downloads,
+        "views": views,
+        "countries": countries
+            .into_iter()
+            .filter(|x| !x.country.is_empty())
+            .map(|x| (x.country, x.downloads))
+            .collect::<HashMap<String, u64>>()
+    })))
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Day,
+    Hour,
+}
+
+impl Resolution {
+    /// The expression a `recorded` column is grouped by for this resolution
+    fn bucket(self) -> &'static str {
+        match self {
+            Resolution::Day => "toStartOfDay(recorded)",
+            Resolution::Hour => "toStartOfHour(recorded)",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DownloadsQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    resolution: Resolution,
+    // Include downloads by the project's own team, which are left out by default
+    #[serde(default)]
+    include_owner: bool,
+    format: Option<Format>,
+}
+
+/// Internal route - retrieves a project's counted downloads over time, bucketed by day or hour
+/// (UTC). Buckets without any downloads are omitted.
+#[get("v1/downloads", guard = "admin_key_guard")]
+pub async fn downloads_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<DownloadsQuery>,
+    client: web::Data<clickhouse::Client>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = parse_base62(&query.project_id)
+        .map_err(|_| ApiError::InvalidInput("invalid project ID specified!".to_string()))?;
+
+    check_range(query.start_date, query.end_date)?;
+    let bucket = query.resolution.bucket();
+
+    #[derive(Serialize, Deserialize, Row)]
+    struct DownloadsBucket {
+        // Unix timestamp of the start of the bucket
+        pub timestamp: u32,
+        pub downloads: u64,
+    }
+
+    let buckets = client
+        .query(&format!(
+            r#"
+            SELECT toUnixTimestamp({bucket}) timestamp, COUNT(id) downloads
+            FROM downloads
+            WHERE project_id = ? AND recorded BETWEEN ? AND ? AND counted AND (? OR NOT owner)
+            GROUP BY timestamp
+            ORDER BY timestamp
+            "#
+        ))
+        .bind(project_id)
+        .bind(time::to_query_ts(query.start_date))
+        .bind(time::to_query_ts(query.end_date))
+        .bind(query.include_owner)
+        .fetch_all::<DownloadsBucket>()
+        .await?;
+
+    if Format::of(&req, query.format) == Format::Csv {
+        return csv_response(&format!("downloads-{}.csv", query.project_id), buckets);
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "buckets": buckets
+            .into_iter()
+            .map(|x| json!({
+                "timestamp": x.timestamp,
+                "downloads": x.downloads,
+            }))
+            .collect::<Vec<_>>()
     })))
 }
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CountryMetric {
+    Downloads,
+    Views,
+}
+
+#[derive(Deserialize)]
+pub struct CountriesQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    metric: CountryMetric,
+    // Include traffic from the project's own team, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+    format: Option<Format>,
+}
+
+/// Retrieves a project's counted downloads or views per country, as a map of ISO country code to
+/// count. Those without a known country, including those from private IPs, are counted under `XX`.
+#[get("v1/countries")]
+pub async fn countries_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<CountriesQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        false,
+    )
+    .await?;
+
+    let (table, count, filter) = match query.metric {
+        CountryMetric::Downloads => ("downloads", "COUNT(id)", "AND counted"),
+        CountryMetric::Views => ("views", WEIGHTED_VIEWS, ""),
+    };
+
+    #[derive(Serialize, Deserialize, Row)]
+    struct CountryCount {
+        pub country: String,
+        pub count: u64,
+    }
+
+    let countries = client
+        .query(&format!(
+            r#"
+            SELECT if(country = '', '{PRIVATE_COUNTRY}', country) code, {count} count
+            FROM {table}
+            WHERE project_id = ? AND recorded BETWEEN ? AND ? AND (? OR NOT owner) {filter}
+            GROUP BY code
+            "#
+        ))
+        .bind(project_id)
+        .bind(time::to_query_ts(query.start_date))
+        .bind(time::to_query_ts(query.end_date))
+        .bind(query.include_owner)
+        .fetch_all::<CountryCount>()
+        .await?
+        .into_iter();
+
+    if Format::of(&req, query.format) == Format::Csv {
+        return csv_response(&format!("countries-{}.csv", query.project_id), countries);
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "countries": countries
+            .map(|x| (x.country, x.count))
+            .collect::<HashMap<String, u64>>()
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct SummaryQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+    // Include traffic from the project's own team, which is left out by default
+    #[serde(default)]
+    include_owner: bool,
+}
+
+/// Retrieves a project's counted views and downloads in a range, and how many known countries
+/// they came from, so a dashboard can show its overview in one request
+#[get("v1/summary")]
+pub async fn summary_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<SummaryQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        false,
+    )
+    .await?;
+
+    let (totals, countries) = futures::future::try_join(
+        project_totals(
+            &client,
+            project_id,
+            Some((query.start_date, query.end_date)),
+            query.include_owner,
+        ),
+        client
+            .query(&format!(
+                r#"
+                SELECT uniqExact(country)
+                FROM (
+                    SELECT country
+                    FROM views
+                    WHERE project_id = ? AND recorded BETWEEN ? AND ? AND (? OR NOT owner)
+                    UNION ALL
+                    SELECT country
+                    FROM downloads
+                    WHERE project_id = ? AND recorded BETWEEN ? AND ? AND counted
+                        AND (? OR NOT owner)
+                )
+                WHERE country NOT IN ('', '{PRIVATE_COUNTRY}')
+                "#
+            ))
+            .bind(project_id)
+            .bind(time::to_query_ts(query.start_date))
+            .bind(time::to_query_ts(query.end_date))
+            .bind(query.include_owner)
+            .bind(project_id)
+            .bind(time::to_query_ts(query.start_date))
+            .bind(time::to_query_ts(query.end_date))
+            .bind(query.include_owner)
+            .fetch_one::<u64>(),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "views": totals.views,
+        "downloads": totals.downloads,
+        "countries": countries,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct RevenueQuery {
+    project_id: String,
+    start_date: DateTime<Utc>,
+    end_date: DateTime<Utc>,
+}
+
+/// Retrieves a project's revenue in a range, in total and per day (UTC) as a map of `YYYY-MM-DD`
+/// to amount. Revenue is stored in ClickHouse alongside the analytics it is paid out on, and
+/// requires the permission to view the project's payouts rather than its analytics. Days without
+/// any revenue are omitted.
+#[get("v1/revenue")]
+pub async fn revenue_query(
+    req: HttpRequest,
+    web::Query(query): web::Query<RevenueQuery>,
+    client: web::Data<clickhouse::Client>,
+    authorizer: web::Data<Arc<Authorizer>>,
+) -> Result<HttpResponse, ApiError> {
+    let project_id = authorize_project_range(
+        &req,
+        &authorizer,
+        &query.project_id,
+        query.start_date,
+        query.end_date,
+        true,
+    )
+    .await?;
+
+    #[derive(Deserialize, Row)]
+    struct DailyRevenue {
+        pub day: String,
+        pub amount: f64,
+    }
+
+    let days = client
+        .query(
+            r#"
+            SELECT toString(toDate(recorded)) day, sum(amount) amount
+            FROM revenue
+            WHERE project_id = ? AND recorded BETWEEN ? AND ?
+            GROUP BY day
+            "#,
+        )
+        .bind(project_id)
+        .bind(time::to_query_ts(query.start_date))
+        .bind(time::to_query_ts(query.end_date))
+        .fetch_all::<DailyRevenue>()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "total": days.iter().map(|x| x.amount).sum::<f64>(),
+        "days": days
+            .into_iter()
+            .map(|x| (x.day, x.amount))
+            .collect::<HashMap<String, f64>>(),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_client;
+    use crate::models::downloads::Download;
+    use crate::models::views::PageView;
+    use crate::scheduled::analytics::AnalyticsQueue;
+    use crate::util::config::tests::config_from;
+    use crate::util::guards::{set_admin_keys, ADMIN_KEY_HEADER};
+    use actix_web::App;
+    use chrono::{SecondsFormat, TimeZone};
+    use std::net::Ipv6Addr;
+
+    fn view(project_id: u64, sample_weight: f64) -> PageView {
+        PageView {
+            id: Uuid::new_v4(),
+            recorded: time::record_now(),
+            domain: "modrinth.com".to_string(),
+            site_path: "/mod/sodium".to_string(),
+            full_path: String::new(),
+            referrer_domain: String::new(),
+            from_server: false,
+            user_id: 0,
+            project_id,
+            ip: Ipv6Addr::LOCALHOST,
+            country: "US".to_string(),
+            city: None,
+            asn: 0,
+            asn_org: String::new(),
+            user_agent: "Mozilla/5.0".to_string(),
+            user_agent_kind: "browser".to_string(),
+            headers: Vec::new(),
+            sample_weight,
+            owner: false,
+            respected_dnt: false,
+        }
+    }
+
+    fn download(project_id: u64) -> Download {
+        Download {
+            id: Uuid::new_v4(),
+            recorded: time::record_now(),
+            domain: "cdn.modrinth.com".to_string(),
+            site_path: "/data".to_string(),
+            full_path: String::new(),
+            user_id: 0,
+            project_id,
+            version_id: 1,
+            ip: Ipv6Addr::LOCALHOST,
+            country: "US".to_string(),
+            subdivision: "WA".to_string(),
+            asn: 0,
+            asn_org: String::new(),
+            user_agent: "Mozilla/5.0".to_string(),
+            user_agent_kind: "browser".to_string(),
+            headers: Vec::new(),
+            range: String::new(),
+            counted: true,
+            owner: false,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn round_trips_through_clickhouse() {
+        let Some(client) = test_client().await else {
+            return;
+        };
+
+        let queue = AnalyticsQueue::new(None, None);
+        for _ in 0..3 {
+            queue.add_view(view(1, 1.0)).await;
+        }
+        queue.add_view(view(2, 1.0)).await;
+        for _ in 0..2 {
+            queue.add_download(download(1)).await;
+        }
+        let counts = queue.index(client.clone()).await.unwrap();
+        assert_eq!((counts.views, counts.downloads), (4, 2));
+
+        let start = Utc::now() - Duration::hours(1);
+        let end = Utc::now() + Duration::hours(1);
+
+        let (config, _) = config_from(&[]);
+        let multipliers = collect_multipliers(&client, &config, &FeatureFlags::new(), start, end)
+            .await
+            .unwrap();
+        assert_eq!(
+            multipliers,
+            json!({ "sum": 4, "values": { "1": 3, "2": 1 } })
+        );
+
+        let totals = project_totals(&client, 1, Some((start, end)), false)
+            .await
+            .unwrap();
+        assert_eq!((totals.downloads, totals.views), (2, 3));
+
+        // Rows recorded in the wrong unit would land far outside the range either way
+        let totals = project_totals(&client, 1, Some((start - Duration::hours(1), start)), false)
+            .await
+            .unwrap();
+        assert_eq!((totals.downloads, totals.views), (0, 0));
+
+        set_admin_keys(vec!["key".to_string()]);
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(client))
+                .service(downloads_query),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!(
+                "/v1/downloads?project_id=1&start_date={}&end_date={}&resolution=hour",
+                start.to_rfc3339_opts(SecondsFormat::Secs, true),
+                end.to_rfc3339_opts(SecondsFormat::Secs, true),
+            ))
+            .insert_header((ADMIN_KEY_HEADER, "key"))
+            .to_request();
+        let body: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+
+        let buckets = body["buckets"].as_array().unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0]["downloads"], 2);
+    }
+
+    #[actix_rt::test]
+    async fn weights_views_by_sample_weight() {
+        let Some(client) = test_client().await else {
+            return;
+        };
+
+        let queue = AnalyticsQueue::new(None, None);
+        for _ in 0..3 {
+            queue.add_view(view(1, 1.0)).await;
+        }
+        // One sampled view standing for 2.5 views and one oversampled view for 0.4
+        queue.add_view(view(2, 2.5)).await;
+        queue.add_view(view(2, 0.4)).await;
+        queue.index(client.clone()).await.unwrap();
+
+        let start = Utc::now() - Duration::hours(1);
+        let end = Utc::now() + Duration::hours(1);
+
+        assert_eq!(multiplier_sum(&client, start, end).await.unwrap(), 6);
+
+        let mut cursor = multiplier_values(&client, start, end, Order::Asc, None).unwrap();
+        let mut values = Vec::new();
+        while let Some(row) = cursor.next().await.unwrap() {
+            values.push((row.project_id, row.page_views));
+        }
+        // The 2.9 weighted views of project 2 round to 3, tying with project 1
+        assert_eq!(values, [(1, 3), (2, 3)]);
+
+        let totals = project_totals(&client, 2, Some((start, end)), false)
+            .await
+            .unwrap();
+        assert_eq!(totals.views, 3);
+    }
+
+    #[test]
+    fn parses_timezones() {
+        assert_eq!(parse_timezone(None).unwrap(), Tz::UTC);
+        assert_eq!(
+            parse_timezone(Some("Europe/Berlin")).unwrap(),
+            Tz::Europe__Berlin
+        );
+        assert!(matches!(
+            parse_timezone(Some("Mars/Olympus_Mons")),
+            Err(ApiError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_empty_range() {
+        let start = Utc.ymd(2023, 1, 1).and_hms(0, 0, 0);
+
+        assert!(check_range(start, start + Duration::hours(1)).is_ok());
+        assert!(matches!(
+            check_range(start, start),
+            Err(ApiError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            check_range(start, start - Duration::days(1)),
+            Err(ApiError::InvalidInput(_))
+        ));
+    }
+
+    fn multipliers_query(query: &str) -> Result<MultipliersQuery, actix_web::Error> {
+        Ok(web::Query::<MultipliersQuery>::from_query(query)?.into_inner())
+    }
+
+    #[test]
+    fn pages_multipliers() {
+        let query = multipliers_query("start_date=2023-01-01T00:00:00Z").unwrap();
+        assert_eq!(query.page(), None);
+        assert_eq!(query.order.as_sql(), "DESC");
+
+        let query =
+            multipliers_query("start_date=2023-01-01T00:00:00Z&limit=50&offset=100&order=asc")
+                .unwrap();
+        assert_eq!(query.page(), Some((50, 100)));
+        assert_eq!(query.order.as_sql(), "ASC");
+
+        // Oversized pages are clamped rather than rejected
+        let query = multipliers_query("start_date=2023-01-01T00:00:00Z&limit=1000000").unwrap();
+        assert_eq!(query.page(), Some((MAX_MULTIPLIERS_LIMIT, 0)));
+    }
+
+    #[test]
+    fn rejects_invalid_paging() {
+        for query in [
+            "start_date=2023-01-01T00:00:00Z&order=sideways",
+            "start_date=2023-01-01T00:00:00Z&limit=-1",
+            "start_date=2023-01-01T00:00:00Z&offset=many",
+        ] {
+            assert!(multipliers_query(query).is_err(), "{query}");
+        }
+    }
+
+    #[test]
+    fn ranges_multipliers() {
+        let day = |d| Utc.ymd(2023, 1, d).and_hms(0, 0, 0);
+
+        // Defaults to the day containing the start
+        let query = multipliers_query("start_date=2023-01-01T13:45:00Z").unwrap();
+        assert_eq!(query.range().unwrap(), (day(1), day(2)));
+
+        let query =
+            multipliers_query("start_date=2023-01-01T13:45:00Z&end_date=2023-01-08T00:00:00Z")
+                .unwrap();
+        assert_eq!(query.range().unwrap(), (day(1), day(8)));
+    }
+
+    #[test]
+    fn rejects_invalid_multiplier_ranges() {
+        for query in [
+            // Inverted
+            "start_date=2023-01-08T00:00:00Z&end_date=2023-01-01T00:00:00Z",
+            // Empty
+            "start_date=2023-01-01T00:00:00Z&end_date=2023-01-01T00:00:00Z",
+            // Too long
+            "start_date=2023-01-01T00:00:00Z&end_date=2024-01-01T00:00:00Z",
+        ] {
+            assert!(
+                matches!(
+                    multipliers_query(query).unwrap().range(),
+                    Err(ApiError::InvalidInput(_))
+                ),
+                "{query}"
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn maps_downloads_by_subdivision() {
+        let Some(client) = test_client().await else {
+            return;
+        };
+
+        let queue = AnalyticsQueue::new(None, None);
+        for _ in 0..2 {
+            queue.add_download(download(1)).await;
+        }
+        queue
+            .add_download(Download {
+                subdivision: "OR".to_string(),
+                ..download(1)
+            })
+            .await;
+        queue
+            .add_download(Download {
+                country: "CA".to_string(),
+                subdivision: String::new(),
+                ..download(1)
+            })
+            .await;
+        queue.index(client.clone()).await.unwrap();
+
+        let start = Utc::now() - Duration::hours(1);
+        let end = Utc::now() + Duration::hours(1);
+        let regions = |granularity| download_regions(&client, 1, start, end, false, granularity);
+
+        assert_eq!(
+            regions(MapGranularity::Country).await.unwrap(),
+            HashMap::from([("US".to_string(), 3), ("CA".to_string(), 1)])
+        );
+        assert_eq!(
+            regions(MapGranularity::Subdivision).await.unwrap(),
+            HashMap::from([
+                ("US-WA".to_string(), 2),
+                ("US-OR".to_string(), 1),
+                ("CA".to_string(), 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_map_granularity() {
+        let map_query = |query: &str| {
+            web::Query::<DownloadsMapQuery>::from_query(&format!(
+                "project_id=AABBCCDD&start_date=2023-01-01T00:00:00Z&end_date=2023-01-02T00:00:00Z{query}"
+            ))
+            .map(|x| x.into_inner().granularity)
+        };
+
+        assert!(matches!(map_query(""), Ok(MapGranularity::Country)));
+        assert!(matches!(
+            map_query("&granularity=subdivision"),
+            Ok(MapGranularity::Subdivision)
+        ));
+        // Nothing finer than a subdivision can be asked for
+        assert!(map_query("&granularity=city").is_err());
+    }
+}
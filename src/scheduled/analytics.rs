@@ -1,54 +1,456 @@
+use crate::models::download_sessions::DownloadSession;
 use crate::models::downloads::Download;
+use crate::models::revenue::Revenue;
 use crate::models::views::PageView;
+use crate::util::time;
+use chrono::{DateTime, Utc};
+use clickhouse::Row;
 use dashmap::DashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+use tokio::sync::{Mutex, Notify};
+
+/// Data points of one kind awaiting a flush. The set sits behind a lock only so a flush can swap
+/// it for an empty one: adding takes the lock shared, so data points are still added concurrently,
+/// while `take` briefly holds it exclusively. Every data point thus lands either in the taken set
+/// or in its replacement, and none are copied.
+struct Buffer<T: Eq + Hash>(RwLock<DashSet<T>>);
+
+impl<T: Eq + Hash> Buffer<T> {
+    fn new() -> Self {
+        Buffer(RwLock::new(DashSet::with_capacity(1000)))
+    }
+
+    fn insert(&self, value: T) {
+        self.read().insert(value);
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, DashSet<T>> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn take(&self) -> DashSet<T> {
+        let mut set = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut *set, DashSet::with_capacity(1000))
+    }
+
+    // Puts taken data points back, alongside any added since
+    fn put_back(&self, values: DashSet<T>) {
+        let set = self.read();
+        for value in values {
+            set.insert(value);
+        }
+    }
+}
+
+/// The queued data points, as persisted to disk across restarts
+#[derive(Serialize, Deserialize)]
+struct PersistedQueue {
+    views: Vec<PageView>,
+    downloads: Vec<Download>,
+    download_sessions: Vec<DownloadSession>,
+    revenue: Vec<Revenue>,
+}
 
 pub struct AnalyticsQueue {
-    views_queue: DashSet<PageView>,
-    downloads_queue: DashSet<Download>,
+    views_queue: Buffer<PageView>,
+    downloads_queue: Buffer<Download>,
+    download_sessions_queue: Buffer<DownloadSession>,
+    revenue_queue: Buffer<Revenue>,
+    // Data points are dropped while this many are queued, so a stalled flush can't exhaust memory
+    max_entries: Option<usize>,
+    // Data points dropped because the queue was full, since startup
+    dropped: AtomicU64,
+    // A flush is requested out of band once this many are queued, so bursts aren't held in
+    // memory until the next scheduled flush
+    flush_threshold: Option<usize>,
+    flush_requested: Notify,
+    // Held while flushing, so scheduled, requested and manual flushes run one at a time
+    flush_lock: Mutex<()>,
 }
 
 // Batches analytics data points + transactions every few minutes
 impl AnalyticsQueue {
-    pub fn new() -> Self {
+    pub fn new(max_entries: Option<usize>, flush_threshold: Option<usize>) -> Self {
         AnalyticsQueue {
-            views_queue: DashSet::with_capacity(1000),
-            downloads_queue: DashSet::with_capacity(1000),
+            views_queue: Buffer::new(),
+            downloads_queue: Buffer::new(),
+            download_sessions_queue: Buffer::new(),
+            revenue_queue: Buffer::new(),
+            max_entries,
+            dropped: AtomicU64::new(0),
+            flush_threshold,
+            flush_requested: Notify::new(),
+            flush_lock: Mutex::new(()),
         }
     }
 
     pub async fn add_view(&self, page_view: PageView) {
-        self.views_queue.insert(page_view);
+        if self.has_room() {
+            self.views_queue.insert(page_view);
+            self.request_flush_if_full();
+        }
     }
 
     pub async fn add_download(&self, download: Download) {
-        self.downloads_queue.insert(download);
+        if self.has_room() {
+            self.downloads_queue.insert(download);
+            self.request_flush_if_full();
+        }
     }
 
-    pub async fn index(&self, client: clickhouse::Client) -> Result<(), clickhouse::error::Error> {
-        let views_queue = self.views_queue.clone();
-        self.views_queue.clear();
+    pub async fn add_download_session(&self, download_session: DownloadSession) {
+        if self.has_room() {
+            self.download_sessions_queue.insert(download_session);
+            self.request_flush_if_full();
+        }
+    }
 
-        let downloads_queue = self.downloads_queue.clone();
-        self.downloads_queue.clear();
+    pub async fn add_revenue(&self, revenue: Revenue) {
+        if self.has_room() {
+            self.revenue_queue.insert(revenue);
+            self.request_flush_if_full();
+        }
+    }
 
-        if !views_queue.is_empty() || !downloads_queue.is_empty() {
-            let mut views = client.insert("views")?;
+    // Counts the data point as dropped if the queue is full
+    fn has_room(&self) -> bool {
+        let full = self.max_entries.is_some_and(|max| self.len() >= max);
+        if full {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
 
-            for view in views_queue {
-                views.write(&view).await?;
-            }
+        !full
+    }
+
+    fn request_flush_if_full(&self) {
+        if self.flush_threshold.is_some_and(|x| self.len() >= x) {
+            // Requests made while a flush is running are kept, so a flush follows it right away
+            self.flush_requested.notify_one();
+        }
+    }
+
+    /// Waits until the queue reaches its flush threshold, never returning if there is none
+    pub async fn flush_requested(&self) {
+        self.flush_requested.notified().await;
+    }
+
+    /// How many data points are queued
+    pub fn len(&self) -> usize {
+        self.views_queue.read().len()
+            + self.downloads_queue.read().len()
+            + self.download_sessions_queue.read().len()
+            + self.revenue_queue.read().len()
+    }
+
+    /// How many data points were dropped because the queue was full, since startup
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn downloads_len(&self) -> usize {
+        self.downloads_queue.read().len()
+    }
+
+    /// Summarizes the buffered data points without clearing or mutating the queue
+    pub fn snapshot(&self, top: usize) -> QueueSnapshot {
+        let views_queue = self.views_queue.read();
+        let downloads_queue = self.downloads_queue.read();
+        let download_sessions_queue = self.download_sessions_queue.read();
+        let revenue_queue = self.revenue_queue.read();
+
+        let oldest = views_queue
+            .iter()
+            .map(|x| x.recorded)
+            .chain(downloads_queue.iter().map(|x| x.recorded))
+            .chain(download_sessions_queue.iter().map(|x| x.recorded))
+            .chain(revenue_queue.iter().map(|x| x.recorded))
+            .min();
+
+        let views = views_queue.len();
+        let downloads = downloads_queue.len();
+        let download_sessions = download_sessions_queue.len();
+        let revenue = revenue_queue.len();
+
+        QueueSnapshot {
+            total: views + downloads + download_sessions + revenue,
+            oldest_recorded: oldest.and_then(time::from_record_ts),
+            views,
+            downloads,
+            download_sessions,
+            revenue,
+            top_viewed_projects: top_projects(views_queue.iter().map(|x| x.project_id), top),
+            top_downloaded_projects: top_projects(
+                downloads_queue.iter().map(|x| x.project_id),
+                top,
+            ),
+        }
+    }
+
+    /// Moves every queued data point to a JSON file at `path`, so they survive a restart. Returns
+    /// how many were written.
+    pub fn persist(&self, path: &Path) -> std::io::Result<usize> {
+        let queue = PersistedQueue {
+            views: self.views_queue.take().into_iter().collect(),
+            downloads: self.downloads_queue.take().into_iter().collect(),
+            download_sessions: self.download_sessions_queue.take().into_iter().collect(),
+            revenue: self.revenue_queue.take().into_iter().collect(),
+        };
+        let count = queue.views.len()
+            + queue.downloads.len()
+            + queue.download_sessions.len()
+            + queue.revenue.len();
+
+        // Written next to the target first, so a crash mid-write doesn't leave a truncated file
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, serde_json::to_vec(&queue)?)?;
+        std::fs::rename(temp_path, path)?;
+
+        Ok(count)
+    }
+
+    /// Re-enqueues the data points persisted at `path` and removes the file, so they aren't
+    /// restored twice. Returns how many were restored, which is 0 if nothing was persisted.
+    pub fn restore(&self, path: &Path) -> std::io::Result<usize> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let queue = serde_json::from_slice::<PersistedQueue>(&bytes)?;
+        std::fs::remove_file(path)?;
+
+        let count = queue.views.len()
+            + queue.downloads.len()
+            + queue.download_sessions.len()
+            + queue.revenue.len();
+
+        queue
+            .views
+            .into_iter()
+            .for_each(|x| self.views_queue.insert(x));
+        queue
+            .downloads
+            .into_iter()
+            .for_each(|x| self.downloads_queue.insert(x));
+        queue
+            .download_sessions
+            .into_iter()
+            .for_each(|x| self.download_sessions_queue.insert(x));
+        queue
+            .revenue
+            .into_iter()
+            .for_each(|x| self.revenue_queue.insert(x));
+
+        Ok(count)
+    }
+
+    /// Writes every queued data point to ClickHouse, returning how many of each kind were written
+    pub async fn index(&self, client: clickhouse::Client) -> Result<FlushCounts, FlushError> {
+        let _flush = self.flush_lock.lock().await;
 
-            views.end().await?;
+        let views_queue = self.views_queue.take();
+        let downloads_queue = self.downloads_queue.take();
+        let download_sessions_queue = self.download_sessions_queue.take();
+        let revenue_queue = self.revenue_queue.take();
 
-            let mut downloads = client.insert("downloads")?;
+        let counts = FlushCounts {
+            views: views_queue.len(),
+            downloads: downloads_queue.len(),
+            download_sessions: download_sessions_queue.len(),
+            revenue: revenue_queue.len(),
+        };
+        let pending = counts.views + counts.downloads + counts.download_sessions + counts.revenue;
 
-            for download in downloads_queue {
-                downloads.write(&download).await?;
+        // Tables are written in order, so when one fails it and the ones after it are put back
+        // to be written by the next flush. The tables before it were written and aren't.
+        let mut written = 0;
+        let result = async {
+            insert_rows(&client, "views", &views_queue).await?;
+            written += 1;
+            insert_rows(&client, "downloads", &downloads_queue).await?;
+            written += 1;
+            insert_rows(&client, "download_sessions", &download_sessions_queue).await?;
+            written += 1;
+            insert_rows(&client, "revenue", &revenue_queue).await
+        }
+        .await;
+
+        if result.is_err() {
+            if written < 1 {
+                self.views_queue.put_back(views_queue);
+            }
+            if written < 2 {
+                self.downloads_queue.put_back(downloads_queue);
+            }
+            if written < 3 {
+                self.download_sessions_queue
+                    .put_back(download_sessions_queue);
             }
+            self.revenue_queue.put_back(revenue_queue);
+        }
+
+        result.map(|_| counts).map_err(|source| FlushError {
+            kind: FlushErrorKind::of(&source),
+            pending,
+            source,
+        })
+    }
+}
+
+/// How many data points of each kind a flush wrote
+#[derive(Serialize)]
+pub struct FlushCounts {
+    pub views: usize,
+    pub downloads: usize,
+    pub download_sessions: usize,
+    pub revenue: usize,
+}
+
+async fn insert_rows<T>(
+    client: &clickhouse::Client,
+    table: &str,
+    rows: &DashSet<T>,
+) -> Result<(), clickhouse::error::Error>
+where
+    T: Row + Serialize + Eq + Hash,
+{
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    let mut insert = client.insert(table)?;
+
+    for row in rows.iter() {
+        insert.write(&*row).await?;
+    }
+
+    insert.end().await
+}
+
+/// What went wrong while flushing, to tell retryable failures from ones that will recur
+#[derive(Debug, Clone, Copy)]
+pub enum FlushErrorKind {
+    /// ClickHouse couldn't be reached or timed out - retrying may succeed
+    Connection,
+    /// ClickHouse rejected the insert, e.g. due to a schema mismatch or resource limits
+    Rejected,
+    /// The rows couldn't be encoded or the client is misconfigured - retrying won't help
+    Invalid,
+}
+
+impl FlushErrorKind {
+    fn of(error: &clickhouse::error::Error) -> Self {
+        use clickhouse::error::Error;
+
+        match error {
+            Error::Network(..) | Error::TimedOut => FlushErrorKind::Connection,
+            Error::BadResponse(..) => FlushErrorKind::Rejected,
+            _ => FlushErrorKind::Invalid,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlushErrorKind::Connection => "connection",
+            FlushErrorKind::Rejected => "rejected",
+            FlushErrorKind::Invalid => "invalid",
+        }
+    }
+}
+
+impl std::fmt::Display for FlushErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{kind} error with {pending} rows pending: {source}")]
+pub struct FlushError {
+    pub kind: FlushErrorKind,
+    // The number of rows that were being flushed when the error occurred
+    pub pending: usize,
+    #[source]
+    pub source: clickhouse::error::Error,
+}
+
+#[derive(Serialize)]
+pub struct QueueSnapshot {
+    pub total: usize,
+    pub oldest_recorded: Option<DateTime<Utc>>,
+    pub views: usize,
+    pub downloads: usize,
+    pub download_sessions: usize,
+    pub revenue: usize,
+    // (project ID, buffered entries), highest first
+    pub top_viewed_projects: Vec<(u64, usize)>,
+    pub top_downloaded_projects: Vec<(u64, usize)>,
+}
+
+fn top_projects(project_ids: impl Iterator<Item = u64>, top: usize) -> Vec<(u64, usize)> {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for project_id in project_ids {
+        *counts.entry(project_id).or_default() += 1;
+    }
+
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_unstable_by_key(|x| std::cmp::Reverse(x.1));
+    counts.truncate(top);
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn revenue() -> Revenue {
+        Revenue {
+            id: Uuid::new_v4(),
+            recorded: time::record_now(),
+            project_id: 1,
+            amount: 1.0,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn failed_flush_keeps_rows() {
+        let queue = AnalyticsQueue::new(None, None);
+        queue.add_revenue(revenue()).await;
+        queue.add_revenue(revenue()).await;
+
+        // Nothing listens on the discard port, so the insert fails to connect
+        let client = clickhouse::Client::default().with_url("http://127.0.0.1:9");
+        let error = queue.index(client).await.err().unwrap();
+
+        assert_eq!(error.pending, 2);
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn empty_flush_succeeds() {
+        let queue = AnalyticsQueue::new(None, None);
+
+        let client = clickhouse::Client::default().with_url("http://127.0.0.1:9");
+        let counts = queue.index(client).await.unwrap();
+
+        assert_eq!(counts.revenue, 0);
+        assert_eq!(queue.len(), 0);
+    }
 
-            downloads.end().await?;
+    #[actix_rt::test]
+    async fn drops_past_the_cap() {
+        let queue = AnalyticsQueue::new(Some(2), None);
+        for _ in 0..5 {
+            queue.add_revenue(revenue()).await;
         }
 
-        Ok(())
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped(), 3);
     }
 }
@@ -0,0 +1,114 @@
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+/// Collapses repeated downloads of a version from a single IP within a window into one counted
+/// download, so retried downloads aren't counted twice. Repeats are still stored, just not counted,
+/// so they remain available for fraud analysis.
+///
+/// IPs are only kept as SHA-256 hashes peppered with `RATE_LIMIT_PEPPER`, like the view rate limit.
+pub struct DownloadDedup {
+    // SHA-256 of the pepper, IP and version ID -> when the version was first downloaded this window
+    first_counted: DashMap<[u8; 32], Instant>,
+    pepper: String,
+    // `None` if deduplication is disabled
+    window: Option<Duration>,
+}
+
+impl DownloadDedup {
+    pub fn new(pepper: String, window: Option<Duration>) -> Self {
+        DownloadDedup {
+            first_counted: DashMap::new(),
+            pepper,
+            window: window.filter(|x| !x.is_zero()),
+        }
+    }
+
+    /// Whether a download of the version from this IP should be counted, i.e. whether it is the
+    /// first one in the window. Unlike the download throttle, the window runs from the first
+    /// counted download, so repeats never extend it.
+    pub fn count(&self, ip: Ipv6Addr, version_id: u64) -> bool {
+        let Some(window) = self.window else {
+            return true;
+        };
+
+        let key = Sha256::new()
+            .chain_update(&self.pepper)
+            .chain_update(ip.octets())
+            .chain_update(version_id.to_le_bytes())
+            .finalize()
+            .into();
+
+        let now = Instant::now();
+        let mut counted = true;
+
+        self.first_counted
+            .entry(key)
+            .and_modify(|first_counted| {
+                if now.duration_since(*first_counted) < window {
+                    counted = false;
+                } else {
+                    *first_counted = now;
+                }
+            })
+            .or_insert(now);
+
+        counted
+    }
+
+    /// Forgets downloads first counted longer than the window ago, which no longer affect counting
+    pub fn prune(&self) {
+        if let Some(window) = self.window {
+            self.first_counted
+                .retain(|_, first_counted| first_counted.elapsed() < window);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Option<Duration> = Some(Duration::from_secs(60));
+
+    #[test]
+    fn counts_repeats_in_window_once() {
+        let dedup = DownloadDedup::new("pepper".to_string(), WINDOW);
+        let ip = Ipv6Addr::LOCALHOST;
+
+        assert!(dedup.count(ip, 1));
+        assert!(!dedup.count(ip, 1));
+        assert!(!dedup.count(ip, 1));
+    }
+
+    #[test]
+    fn counts_distinct_versions_and_ips() {
+        let dedup = DownloadDedup::new("pepper".to_string(), WINDOW);
+        let ip = Ipv6Addr::LOCALHOST;
+
+        assert!(dedup.count(ip, 1));
+        assert!(dedup.count(ip, 2));
+        assert!(dedup.count(Ipv6Addr::UNSPECIFIED, 1));
+    }
+
+    #[test]
+    fn counts_again_after_window() {
+        let dedup = DownloadDedup::new("pepper".to_string(), Some(Duration::from_millis(10)));
+        let ip = Ipv6Addr::LOCALHOST;
+
+        assert!(dedup.count(ip, 1));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(dedup.count(ip, 1));
+    }
+
+    #[test]
+    fn counts_everything_when_disabled() {
+        for window in [None, Some(Duration::ZERO)] {
+            let dedup = DownloadDedup::new("pepper".to_string(), window);
+
+            assert!(dedup.count(Ipv6Addr::LOCALHOST, 1));
+            assert!(dedup.count(Ipv6Addr::LOCALHOST, 1));
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use crate::models::downloads::Download;
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+/// Downloads that have been started but not yet completed, keyed by the caller's session ID
+pub struct PendingDownloads {
+    pending: DashMap<String, (Download, Instant)>,
+    timeout: Duration,
+}
+
+impl PendingDownloads {
+    pub fn new(timeout: Duration) -> Self {
+        PendingDownloads {
+            pending: DashMap::new(),
+            timeout,
+        }
+    }
+
+    pub fn start(&self, session_id: String, download: Download) {
+        self.pending.insert(session_id, (download, Instant::now()));
+    }
+
+    /// Takes the session's download if it was started within the timeout. Timed out sessions are
+    /// left for `expire` to collect.
+    pub fn complete(&self, session_id: &str) -> Option<Download> {
+        self.pending
+            .remove_if(session_id, |_, (_, started)| {
+                started.elapsed() < self.timeout
+            })
+            .map(|(_, (download, _))| download)
+    }
+
+    /// Removes and returns every download whose session timed out without being completed
+    pub fn expire(&self) -> Vec<Download> {
+        let mut expired = Vec::new();
+
+        self.pending.retain(|_, (download, started)| {
+            if started.elapsed() < self.timeout {
+                true
+            } else {
+                expired.push(download.clone());
+                false
+            }
+        });
+
+        expired
+    }
+}
@@ -0,0 +1,59 @@
+use dashmap::DashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+/// Enforces a minimum interval between the counted downloads of a project from a single IP.
+/// Downloads inside the interval are still stored, just not counted.
+pub struct DownloadThrottle {
+    // (project ID, hashed IP) -> when a download was last counted
+    last_counted: DashMap<(u64, u64), Instant>,
+    // IPs are only kept in memory as hashes, keyed per process so they can't be reversed
+    hasher: RandomState,
+    // `None` if throttling is disabled
+    interval: Option<Duration>,
+}
+
+impl DownloadThrottle {
+    pub fn new(interval: Option<Duration>) -> Self {
+        DownloadThrottle {
+            last_counted: DashMap::new(),
+            hasher: RandomState::new(),
+            interval: interval.filter(|x| !x.is_zero()),
+        }
+    }
+
+    /// Whether a download of the project from this IP should be counted, marking it as counted if
+    /// so. The interval runs from the last counted download, so a steady stream of downloads is
+    /// still counted once per interval.
+    pub fn count(&self, project_id: u64, ip: Ipv6Addr) -> bool {
+        let Some(interval) = self.interval else {
+            return true;
+        };
+
+        let now = Instant::now();
+        let mut counted = true;
+
+        self.last_counted
+            .entry((project_id, self.hasher.hash_one(ip)))
+            .and_modify(|last_counted| {
+                if now.duration_since(*last_counted) < interval {
+                    counted = false;
+                } else {
+                    *last_counted = now;
+                }
+            })
+            .or_insert(now);
+
+        counted
+    }
+
+    /// Forgets downloads counted longer than the interval ago, which no longer affect counting
+    pub fn prune(&self) {
+        if let Some(interval) = self.interval {
+            self.last_counted
+                .retain(|_, last_counted| last_counted.elapsed() < interval);
+        }
+    }
+}
@@ -1,44 +1,237 @@
+use crate::util::config::Config;
+use crate::util::ip;
 use flate2::read::GzDecoder;
-use log::warn;
-use maxminddb::geoip2::Country;
+use log::{info, warn};
+use maxminddb::geoip2::{Asn, City, Country};
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 use std::io::{Cursor, Read};
 use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
 use tar::Archive;
 use tokio::sync::RwLock;
 
+/// The validators a cached database was downloaded with, to only download it again once MaxMind
+/// has a newer one
+#[derive(Serialize, Deserialize, Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Where the database of an edition is cached, if `MAXMIND_DB_PATH` is set. Its validators are
+/// kept next to it.
+fn cache_path(db_path: Option<&Path>, edition: &str) -> Option<PathBuf> {
+    db_path.map(|dir| dir.join(format!("{edition}.mmdb")))
+}
+
+/// Loads the cached database of an edition, if there is a readable one
+fn load_cached(db_path: Option<&Path>, edition: &str) -> Option<maxminddb::Reader<Vec<u8>>> {
+    let buf = std::fs::read(cache_path(db_path, edition)?).ok()?;
+
+    match maxminddb::Reader::from_source(buf) {
+        Ok(reader) => {
+            info!("Loaded cached maxmind {} database", edition);
+            Some(reader)
+        }
+        Err(e) => {
+            warn!("Cached maxmind {} database is invalid: {}", edition, e);
+            None
+        }
+    }
+}
+
+/// Caches a downloaded database, writing it next to the target first so a crash mid-write
+/// doesn't leave a truncated file
+fn save_cached(
+    db_path: Option<&Path>,
+    edition: &str,
+    buf: &[u8],
+    validators: &Validators,
+) -> std::io::Result<()> {
+    let Some(path) = cache_path(db_path, edition) else {
+        return Ok(());
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, buf)?;
+    std::fs::rename(temp_path, &path)?;
+    std::fs::write(path.with_extension("json"), serde_json::to_vec(validators)?)
+}
+
 pub struct MaxMindIndexer {
-    pub reader: RwLock<maxminddb::Reader<Vec<u8>>>,
+    // The databases are optional, so ingest carries on without geolocation while one can't be
+    // downloaded
+    pub reader: RwLock<Option<maxminddb::Reader<Vec<u8>>>>,
+    pub asn_reader: RwLock<Option<maxminddb::Reader<Vec<u8>>>>,
+    // Only loaded with `MAXMIND_ENABLE_CITY`, as the city database is many times larger
+    pub city_reader: RwLock<Option<maxminddb::Reader<Vec<u8>>>>,
+    city_enabled: bool,
+    license_key: String,
+    // Where downloaded databases are cached, from `MAXMIND_DB_PATH`
+    db_path: Option<PathBuf>,
 }
 
 impl MaxMindIndexer {
-    pub async fn new() -> Result<Self, reqwest::Error> {
-        let reader = MaxMindIndexer::inner_index(true).await?.unwrap();
+    /// Loads the cached databases, downloading those that aren't cached yet. Databases that can't
+    /// be loaded either way are left out until a scheduled refresh succeeds.
+    pub async fn new(config: &Config) -> Self {
+        let mut indexer = MaxMindIndexer {
+            reader: RwLock::new(None),
+            asn_reader: RwLock::new(None),
+            city_reader: RwLock::new(None),
+            city_enabled: config.maxmind_enable_city,
+            license_key: config.maxmind_license_key.clone(),
+            db_path: config.maxmind_db_path.clone(),
+        };
+
+        *indexer.reader.get_mut() = indexer.initial("GeoLite2-Country").await;
+        *indexer.asn_reader.get_mut() = indexer.initial("GeoLite2-ASN").await;
+        if indexer.city_enabled {
+            *indexer.city_reader.get_mut() = indexer.initial("GeoLite2-City").await;
+        }
 
-        Ok(MaxMindIndexer {
+        indexer
+    }
+
+    /// Loads the country database from a local file, for deployments that can't download it. It
+    /// is never refreshed, and the ASN and city databases are left out.
+    pub fn from_file(path: &Path) -> Self {
+        let reader = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|buf| maxminddb::Reader::from_source(buf).map_err(|e| e.to_string()));
+
+        let reader = match reader {
+            Ok(reader) => Some(reader),
+            Err(e) => {
+                warn!(
+                    "Loading local maxmind database at {} failed: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        };
+
+        MaxMindIndexer {
             reader: RwLock::new(reader),
-        })
+            asn_reader: RwLock::new(None),
+            city_reader: RwLock::new(None),
+            city_enabled: false,
+            license_key: String::new(),
+            db_path: None,
+        }
     }
 
+    async fn initial(&self, edition: &str) -> Option<maxminddb::Reader<Vec<u8>>> {
+        if let Some(reader) = load_cached(self.db_path.as_deref(), edition) {
+            return Some(reader);
+        }
+
+        match self.inner_index(edition, false).await {
+            Ok(reader) => reader,
+            Err(e) => {
+                warn!("Downloading maxmind {} database failed: {}", edition, e);
+                None
+            }
+        }
+    }
+
+    /// Replaces the databases MaxMind has published a newer version of since they were downloaded,
+    /// and downloads those that are missing
     pub async fn index(&self) -> Result<(), reqwest::Error> {
-        let reader = MaxMindIndexer::inner_index(false).await?;
+        let country = self.refresh(&self.reader, "GeoLite2-Country").await;
+        let asn = self.refresh(&self.asn_reader, "GeoLite2-ASN").await;
+        let city = if self.city_enabled {
+            self.refresh(&self.city_reader, "GeoLite2-City").await
+        } else {
+            Ok(())
+        };
+
+        country.and(asn).and(city)
+    }
+
+    async fn refresh(
+        &self,
+        lock: &RwLock<Option<maxminddb::Reader<Vec<u8>>>>,
+        edition: &str,
+    ) -> Result<(), reqwest::Error> {
+        // Without a loaded database, it is downloaded even if the cached copy is current
+        let conditional = lock.read().await.is_some();
 
-        if let Some(reader) = reader {
-            let mut reader_new = self.reader.write().await;
-            *reader_new = reader;
+        if let Some(reader) = self.inner_index(edition, conditional).await? {
+            let mut reader_new = lock.write().await;
+            let reader_old = reader_new.replace(reader);
+
+            // Lookups wait on the lock while it's held, so the old database is freed after
+            drop(reader_new);
+            drop(reader_old);
         }
 
         Ok(())
     }
 
+    /// Whether the country database is loaded, i.e. whether views and downloads are geolocated
+    pub async fn is_available(&self) -> bool {
+        self.reader.read().await.is_some()
+    }
+
+    /// Downloads the database of an edition. If `conditional` and a cached copy exists, returns
+    /// `None` without downloading when MaxMind's copy hasn't changed since.
     async fn inner_index(
-        should_panic: bool,
+        &self,
+        edition: &str,
+        conditional: bool,
     ) -> Result<Option<maxminddb::Reader<Vec<u8>>>, reqwest::Error> {
-        let response = reqwest::get(
+        let mut request = reqwest::Client::new().get(
             format!(
-                "https://download.maxmind.com/app/geoip_download?edition_id=GeoLite2-Country&license_key={}&suffix=tar.gz",
-                dotenvy::var("MAXMIND_LICENSE_KEY").unwrap()
+                "https://download.maxmind.com/app/geoip_download?edition_id={}&license_key={}&suffix=tar.gz",
+                edition,
+                self.license_key
             )
-        ).await?.bytes().await.unwrap().to_vec();
+        );
+
+        let cached = cache_path(self.db_path.as_deref(), edition)
+            .filter(|path| conditional && path.exists());
+        if let Some(path) = cached {
+            let validators = std::fs::read(path.with_extension("json"))
+                .ok()
+                .and_then(|x| serde_json::from_slice::<Validators>(&x).ok())
+                .unwrap_or_default();
+
+            if let Some(etag) = validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            info!("maxmind {} database is unchanged", edition);
+
+            return Ok(None);
+        }
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string())
+        };
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+
+        let response = response.bytes().await?.to_vec();
 
         let tarfile = GzDecoder::new(Cursor::new(response));
         let mut archive = Archive::new(tarfile);
@@ -48,9 +241,23 @@ impl MaxMindIndexer {
                 if let Ok(path) = file.header().path() {
                     if path.extension().and_then(|x| x.to_str()) == Some("mmdb") {
                         let mut buf = Vec::new();
-                        file.read_to_end(&mut buf).unwrap();
+                        if file.read_to_end(&mut buf).is_err() {
+                            break;
+                        }
 
-                        let reader = maxminddb::Reader::from_source(buf).unwrap();
+                        let reader = match maxminddb::Reader::from_source(buf.clone()) {
+                            Ok(reader) => reader,
+                            Err(e) => {
+                                warn!("Downloaded maxmind {} database is invalid: {}", edition, e);
+                                return Ok(None);
+                            }
+                        };
+
+                        if let Err(e) =
+                            save_cached(self.db_path.as_deref(), edition, &buf, &validators)
+                        {
+                            warn!("Caching maxmind {} database failed: {}", edition, e);
+                        }
 
                         return Ok(Some(reader));
                     }
@@ -58,21 +265,78 @@ impl MaxMindIndexer {
             }
         }
 
-        if should_panic {
-            panic!("Unable to download maxmind database- did you get a license key?")
-        } else {
-            warn!("Unable to download maxmind database.");
+        warn!(
+            "Unable to download maxmind {} database- did you get a license key?",
+            edition
+        );
 
-            Ok(None)
-        }
+        Ok(None)
     }
 
-    pub async fn query(&self, ip: Ipv6Addr) -> Option<String> {
-        let maxmind = self.reader.read().await;
+    /// Looks up the country of an IP without ever waiting: if the database is being swapped out
+    /// (or a swap is waiting for the current lookups to finish), the country is left unknown
+    /// instead of holding up ingest. Lookups otherwise all share the lock, so they don't contend
+    /// with each other. Private and reserved IPs aren't looked up, and are in `PRIVATE_COUNTRY`.
+    pub fn try_query(&self, ip: Ipv6Addr) -> Option<String> {
+        if ip::is_private(ip) {
+            return Some(ip::PRIVATE_COUNTRY.to_string());
+        }
+
+        let maxmind = self.reader.try_read().ok()?;
 
         maxmind
+            .as_ref()?
             .lookup::<Country>(ip.into())
             .ok()
             .and_then(|x| x.country.and_then(|x| x.iso_code.map(|x| x.to_string())))
     }
+
+    /// Looks up the autonomous system number and organization an IP belongs to, without waiting
+    /// like `try_query`. `None` if the ASN database is unavailable or doesn't know the IP.
+    pub fn try_query_asn(&self, ip: Ipv6Addr) -> Option<(u32, String)> {
+        if ip::is_private(ip) {
+            return None;
+        }
+
+        let maxmind = self.asn_reader.try_read().ok()?;
+
+        let asn = maxmind.as_ref()?.lookup::<Asn>(ip.into()).ok()?;
+
+        Some((
+            asn.autonomous_system_number?,
+            asn.autonomous_system_organization
+                .unwrap_or_default()
+                .to_string(),
+        ))
+    }
+
+    /// Looks up the English name of the city an IP is in, without waiting like `try_query`. `None`
+    /// if city lookups are disabled or the city is unknown.
+    pub fn try_query_city(&self, ip: Ipv6Addr) -> Option<String> {
+        if ip::is_private(ip) {
+            return None;
+        }
+
+        let maxmind = self.city_reader.try_read().ok()?;
+
+        let city = maxmind.as_ref()?.lookup::<City>(ip.into()).ok()?;
+
+        city.city?.names?.get("en").map(|x| x.to_string())
+    }
+
+    /// Looks up the ISO code of the largest subdivision (e.g. state or province) an IP is in,
+    /// without waiting like `try_query`. Only the first of MaxMind's subdivisions is kept, so
+    /// downloads can be mapped without recording where they came from any more precisely. `None`
+    /// if city lookups are disabled or the subdivision is unknown.
+    pub fn try_query_subdivision(&self, ip: Ipv6Addr) -> Option<String> {
+        if ip::is_private(ip) {
+            return None;
+        }
+
+        let maxmind = self.city_reader.try_read().ok()?;
+
+        let city = maxmind.as_ref()?.lookup::<City>(ip.into()).ok()?;
+
+        city.subdivisions?.first()?.iso_code.map(|x| x.to_string())
+    }
 }
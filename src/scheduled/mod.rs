@@ -1,3 +1,8 @@
 pub mod analytics;
+pub mod download_dedup;
+pub mod download_sessions;
+pub mod download_throttle;
 pub mod maxmind;
+pub mod ratelimit;
 pub mod scheduler;
+pub mod webhooks;
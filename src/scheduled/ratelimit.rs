@@ -0,0 +1,243 @@
+use dashmap::DashMap;
+use log::warn;
+use redis::aio::ConnectionManager;
+use sha2::{Digest, Sha256};
+use std::net::Ipv6Addr;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// How often the counts are reset
+pub const WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Where the view counts are kept
+pub enum RateLimitStore {
+    /// In process, so each replica enforces the limit separately
+    Memory(DashMap<String, u32>),
+    /// In Redis, shared between replicas. Counts expire on their own a window after a page's first
+    /// view.
+    Redis(ConnectionManager),
+}
+
+impl RateLimitStore {
+    /// Connects to the Redis server at the URL
+    pub async fn redis(url: &str) -> redis::RedisResult<Self> {
+        let connection = redis::Client::open(url)?.get_connection_manager().await?;
+
+        Ok(RateLimitStore::Redis(connection))
+    }
+}
+
+/// Limits how often a single IP's views of a page are counted. Exactly `limit` views are
+/// accepted per window: the count holds the views accepted so far, so the view that would take it
+/// past the limit is the first rejected. Rejected views aren't counted, and the counts are reset
+/// every `WINDOW` by `index`.
+///
+/// IPv4 addresses are limited individually, while IPv6 addresses share a count with their whole
+/// /64, since a single client is usually handed one and can rotate through it freely. IPs are only
+/// kept as SHA-256 hashes peppered with `RATE_LIMIT_PEPPER`, so the counts can't be traced back to
+/// visitors.
+pub struct RateLimitQueue {
+    store: RateLimitStore,
+    pepper: String,
+    // How many views of a page an IP may make per window
+    limit: u32,
+    window_started: RwLock<Instant>,
+}
+
+impl RateLimitQueue {
+    pub fn new(store: RateLimitStore, pepper: String, limit: u32) -> Self {
+        RateLimitQueue {
+            store,
+            pepper,
+            limit,
+            window_started: RwLock::new(Instant::now()),
+        }
+    }
+
+    /// Records a view of the page. If the IP already viewed it `limit` times this window,
+    /// returns the seconds until the window resets instead.
+    pub async fn add(&self, ip: Ipv6Addr, site_path: &str) -> Result<(), u64> {
+        let hashed_ip = Sha256::new()
+            .chain_update(&self.pepper)
+            .chain_update(bucket(ip).octets())
+            .finalize();
+        let key = format!("{:x}:{}", hashed_ip, site_path);
+
+        match &self.store {
+            RateLimitStore::Memory(counts) => {
+                let mut count = counts.entry(key).or_insert(0);
+
+                if *count >= self.limit {
+                    let elapsed = self
+                        .window_started
+                        .read()
+                        .map(|x| x.elapsed())
+                        .unwrap_or_default();
+
+                    // At least a second, so clients never retry immediately
+                    Err(WINDOW.saturating_sub(elapsed).as_secs().max(1))
+                } else {
+                    *count += 1;
+                    Ok(())
+                }
+            }
+            RateLimitStore::Redis(connection) => {
+                // Views are let through while Redis is unavailable rather than failing ingest
+                self.add_redis(connection.clone(), &format!("ratelimit:{key}"))
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!("Rate limiting with Redis failed: {}", e);
+                        Ok(())
+                    })
+            }
+        }
+    }
+
+    // Rejected views are counted here too, which doesn't change which views are accepted
+    async fn add_redis(
+        &self,
+        mut connection: ConnectionManager,
+        key: &str,
+    ) -> redis::RedisResult<Result<(), u64>> {
+        let count: u32 = redis::cmd("INCR")
+            .arg(key)
+            .query_async(&mut connection)
+            .await?;
+
+        if count == 1 {
+            redis::cmd("EXPIRE")
+                .arg(key)
+                .arg(WINDOW.as_secs())
+                .query_async::<_, ()>(&mut connection)
+                .await?;
+        }
+
+        if count > self.limit {
+            let mut ttl: i64 = redis::cmd("TTL")
+                .arg(key)
+                .query_async(&mut connection)
+                .await?;
+
+            // The expiry wasn't set, e.g. because the connection dropped right after `INCR`, so
+            // set it now to keep the IP from being limited forever
+            if ttl < 0 {
+                redis::cmd("EXPIRE")
+                    .arg(key)
+                    .arg(WINDOW.as_secs())
+                    .query_async::<_, ()>(&mut connection)
+                    .await?;
+                ttl = WINDOW.as_secs() as i64;
+            }
+
+            // At least a second, so clients never retry immediately
+            Ok(Err(ttl.max(1) as u64))
+        } else {
+            Ok(Ok(()))
+        }
+    }
+
+    /// Clears every count, starting a new window. Counts in Redis expire on their own instead.
+    pub fn index(&self) {
+        if let RateLimitStore::Memory(counts) = &self.store {
+            counts.clear();
+        }
+
+        if let Ok(mut window_started) = self.window_started.write() {
+            *window_started = Instant::now();
+        }
+    }
+}
+
+/// The address an IP is counted under: itself for (mapped) IPv4, its /64 prefix for IPv6
+fn bucket(ip: Ipv6Addr) -> Ipv6Addr {
+    if ip.to_ipv4_mapped().is_some() {
+        ip
+    } else {
+        Ipv6Addr::from(u128::from(ip) & !(u64::MAX as u128))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn queue(limit: u32) -> RateLimitQueue {
+        RateLimitQueue::new(
+            RateLimitStore::Memory(DashMap::new()),
+            "pepper".to_string(),
+            limit,
+        )
+    }
+
+    #[actix_rt::test]
+    async fn accepts_exactly_limit_views() {
+        let ip = Ipv4Addr::new(1, 1, 1, 1).to_ipv6_mapped();
+
+        for limit in [1, 5, 100] {
+            let queue = queue(limit);
+
+            for view in 1..=limit {
+                assert_eq!(
+                    queue.add(ip, "/mod/sodium").await,
+                    Ok(()),
+                    "view {view} with limit {limit}"
+                );
+            }
+
+            let retry_after = queue.add(ip, "/mod/sodium").await.unwrap_err();
+            assert!((1..=WINDOW.as_secs()).contains(&retry_after));
+
+            // Other pages and IPs have counts of their own
+            assert_eq!(queue.add(ip, "/mod/lithium").await, Ok(()));
+            assert_eq!(
+                queue
+                    .add(Ipv4Addr::new(1, 1, 1, 2).to_ipv6_mapped(), "/mod/sodium")
+                    .await,
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn buckets_ipv6_by_64() {
+        let ip = "2001:db8:1:2:aaaa:bbbb:cccc:dddd"
+            .parse::<Ipv6Addr>()
+            .unwrap();
+
+        assert_eq!(bucket(ip), "2001:db8:1:2::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(bucket("2001:db8:1:2::1".parse().unwrap()), bucket(ip));
+        assert_ne!(bucket("2001:db8:1:3::1".parse().unwrap()), bucket(ip));
+
+        // IPv4 addresses are limited individually
+        let ipv4 = Ipv4Addr::new(1, 1, 1, 1).to_ipv6_mapped();
+        assert_eq!(bucket(ipv4), ipv4);
+    }
+
+    #[actix_rt::test]
+    async fn shares_count_within_64() {
+        let queue = queue(1);
+
+        assert_eq!(queue.add("2001:db8::1".parse().unwrap(), "/").await, Ok(()));
+        assert!(queue
+            .add("2001:db8::2".parse().unwrap(), "/")
+            .await
+            .is_err());
+        assert_eq!(
+            queue.add("2001:db8:0:1::1".parse().unwrap(), "/").await,
+            Ok(())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn resets_counts_each_window() {
+        let queue = queue(1);
+        let ip = Ipv4Addr::new(1, 1, 1, 1).to_ipv6_mapped();
+
+        assert_eq!(queue.add(ip, "/").await, Ok(()));
+        assert!(queue.add(ip, "/").await.is_err());
+
+        queue.index();
+        assert_eq!(queue.add(ip, "/").await, Ok(()));
+    }
+}
@@ -1,32 +1,360 @@
 use actix_rt::Arbiter;
-use futures::StreamExt;
+use futures::future::{select, Either};
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt};
+use log::{error, warn};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
 use tokio_stream::wrappers::IntervalStream;
 
 pub struct Scheduler {
-    arbiter: Arbiter,
+    // Tasks run on the arbiter's thread, or on the current runtime without one
+    arbiter: Option<Arbiter>,
+    // Set once on shutdown, after which tasks stop being run
+    stop: watch::Sender<bool>,
+    // Completes once a task has stopped, after its last run finished
+    stopped: Vec<oneshot::Receiver<()>>,
+    // Fraction of an interval runs are randomly moved by, so replicas started together don't
+    // all hit MaxMind and ClickHouse at once. 0 runs tasks on exact intervals.
+    jitter: f64,
 }
 
 impl Scheduler {
-    pub fn new() -> Self {
+    pub fn new(jitter: f64) -> Self {
         Scheduler {
-            arbiter: Arbiter::new(),
+            arbiter: Some(Arbiter::new()),
+            stop: watch::channel(false).0,
+            stopped: Vec::new(),
+            jitter: jitter.clamp(0.0, 1.0),
         }
     }
 
-    pub fn run<F, R>(&mut self, interval: std::time::Duration, mut task: F)
+    /// Runs tasks on the current runtime, so tests can pause and advance its clock
+    #[cfg(test)]
+    fn on_current_runtime(jitter: f64) -> Self {
+        Scheduler {
+            arbiter: None,
+            stop: watch::channel(false).0,
+            stopped: Vec::new(),
+            jitter: jitter.clamp(0.0, 1.0),
+        }
+    }
+
+    /// Stops running tasks, waiting for runs in progress to finish
+    pub async fn shutdown(&mut self) {
+        let _ = self.stop.send(true);
+
+        futures::future::join_all(std::mem::take(&mut self.stopped)).await;
+    }
+
+    fn stopping(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut stop = self.stop.subscribe();
+
+        async move {
+            // The only change is to `true`, and an error means the scheduler is gone
+            let _ = stop.changed().await;
+        }
+    }
+
+    fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) {
+        let (stopped, stopped_receiver) = oneshot::channel();
+        self.stopped.push(stopped_receiver);
+
+        let future = async move {
+            future.await;
+            let _ = stopped.send(());
+        };
+
+        match &self.arbiter {
+            Some(arbiter) => {
+                arbiter.spawn(future);
+            }
+            None => {
+                tokio::spawn(future);
+            }
+        }
+    }
+
+    /// Ticks every interval, starting right away. With jitter, the first tick is up to the jitter
+    /// fraction of the interval late, and each interval up to that fraction longer or shorter.
+    fn ticks(&self, interval: Duration) -> BoxStream<'static, ()> {
+        if self.jitter == 0.0 {
+            return IntervalStream::new(actix_rt::time::interval(interval))
+                .map(|_| ())
+                .boxed();
+        }
+
+        let jitter = self.jitter;
+        futures::stream::unfold(
+            interval.mul_f64(jitter * rand::random::<f64>()),
+            move |delay| async move {
+                actix_rt::time::sleep(delay).await;
+
+                Some((
+                    (),
+                    interval.mul_f64(1.0 + jitter * (2.0 * rand::random::<f64>() - 1.0)),
+                ))
+            },
+        )
+        .boxed()
+    }
+
+    /// Runs a task every interval. The first run starts as soon as the task is registered, so e.g.
+    /// a deploy flushes the restored queue right away, unless jitter delays it.
+    pub fn run<F, R>(&mut self, interval: Duration, mut task: F)
+    where
+        F: FnMut() -> R + Send + 'static,
+        R: std::future::Future<Output = ()> + Send + 'static,
+    {
+        // Ticks while the previous run is still going are skipped, so slow runs don't pile up
+        let running = Arc::new(AtomicBool::new(false));
+        let future = self
+            .ticks(interval)
+            .take_until(self.stopping())
+            .for_each_concurrent(2, move |_| {
+                let running = running.clone();
+                let run = (!running.swap(true, Ordering::AcqRel)).then(&mut task);
+
+                async move {
+                    match run {
+                        Some(run) => {
+                            run_caught(run).await;
+                            running.store(false, Ordering::Release);
+                        }
+                        None => warn!(
+                            "Skipped a run of a task scheduled every {:?}, as its last run is ongoing",
+                            interval
+                        ),
+                    }
+                }
+            });
+
+        self.spawn(future);
+    }
+
+    /// Runs a task whenever `trigger` completes, one run at a time
+    pub fn run_on<T, TR, F, R>(&mut self, mut trigger: T, mut task: F)
     where
+        T: FnMut() -> TR + Send + 'static,
+        TR: std::future::Future<Output = ()> + Send + 'static,
         F: FnMut() -> R + Send + 'static,
         R: std::future::Future<Output = ()> + Send + 'static,
     {
-        let future = IntervalStream::new(actix_rt::time::interval(interval))
-            .for_each_concurrent(2, move |_| task());
+        let mut stopping = Box::pin(self.stopping());
+        self.spawn(async move {
+            while let Either::Left(_) = select(Box::pin(trigger()), &mut stopping).await {
+                run_caught(task()).await;
+            }
+        });
+    }
+}
 
-        self.arbiter.spawn(future);
+/// Runs a task, catching a panic so its schedule carries on. Sentry's panic handler still
+/// reports the panic.
+async fn run_caught(run: impl Future<Output = ()>) {
+    if AssertUnwindSafe(run).catch_unwind().await.is_err() {
+        error!("A scheduled task panicked, and is run again when next due");
     }
 }
 
 impl Drop for Scheduler {
     fn drop(&mut self) {
-        self.arbiter.stop();
+        if let Some(arbiter) = &self.arbiter {
+            arbiter.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::revenue::Revenue;
+    use crate::scheduled::analytics::AnalyticsQueue;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::time;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn skips_ticks_while_running() {
+        time::pause();
+        let mut scheduler = Scheduler::on_current_runtime(0.0);
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most_running = Arc::new(AtomicUsize::new(0));
+        {
+            let runs = runs.clone();
+            let most_running = most_running.clone();
+            scheduler.run(Duration::from_secs(10), move || {
+                let runs = runs.clone();
+                let running = running.clone();
+                let most_running = most_running.clone();
+                async move {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now_running, Ordering::SeqCst);
+                    time::sleep(Duration::from_secs(25)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }
+            });
+        }
+
+        // Runs start at 0s and 30s. The ticks at 10s, 20s and 40s fall within a run.
+        time::sleep(Duration::from_secs(45)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(most_running.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_runs_and_stops_ticking() {
+        time::pause();
+        let mut scheduler = Scheduler::on_current_runtime(0.0);
+
+        let started = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicUsize::new(0));
+        {
+            let started = started.clone();
+            let finished = finished.clone();
+            scheduler.run(Duration::from_secs(60), move || {
+                let started = started.clone();
+                let finished = finished.clone();
+                async move {
+                    started.fetch_add(1, Ordering::SeqCst);
+                    time::sleep(Duration::from_secs(10)).await;
+                    finished.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+
+        // The run in progress is waited for rather than cut off
+        scheduler.shutdown().await;
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+
+        time::sleep(Duration::from_secs(60 * 5)).await;
+        assert_eq!(started.load(Ordering::SeqCst), 1);
+    }
+
+    /// How long after the start each of the first `count` ticks came
+    async fn tick_offsets(
+        scheduler: &Scheduler,
+        interval: Duration,
+        count: usize,
+    ) -> Vec<Duration> {
+        let start = time::Instant::now();
+
+        scheduler
+            .ticks(interval)
+            .take(count)
+            .map(|_| start.elapsed())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn jitters_ticks_within_bounds() {
+        time::pause();
+        let interval = Duration::from_secs(100);
+
+        let offsets = tick_offsets(&Scheduler::on_current_runtime(0.5), interval, 50).await;
+        assert!(offsets[0] <= interval / 2, "first tick at {:?}", offsets[0]);
+        for pair in offsets.windows(2) {
+            let gap = pair[1] - pair[0];
+            assert!(
+                (interval / 2..=interval * 3 / 2).contains(&gap),
+                "ticks {gap:?} apart"
+            );
+        }
+
+        // Without jitter, the first tick is immediate and the rest keep the exact interval. The
+        // paused clock still rounds the first tick up to a millisecond.
+        let offsets = tick_offsets(&Scheduler::on_current_runtime(0.0), interval, 3).await;
+        assert!(offsets[0] <= Duration::from_millis(1));
+        assert_eq!(offsets[1] - offsets[0], interval);
+        assert_eq!(offsets[2] - offsets[1], interval);
+    }
+
+    #[tokio::test]
+    async fn keeps_running_after_a_panic() {
+        time::pause();
+        let mut scheduler = Scheduler::on_current_runtime(0.0);
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        {
+            let runs = runs.clone();
+            scheduler.run(Duration::from_secs(10), move || {
+                let run = runs.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    if run == 1 {
+                        panic!("first run fails");
+                    }
+                }
+            });
+        }
+
+        time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn runs_right_away() {
+        time::pause();
+        let mut scheduler = Scheduler::on_current_runtime(0.0);
+
+        let runs = Arc::new(AtomicUsize::new(0));
+        {
+            let runs = runs.clone();
+            scheduler.run(Duration::from_secs(60 * 60), move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+                async {}
+            });
+        }
+
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn flushes_at_the_threshold() {
+        time::pause();
+        let mut scheduler = Scheduler::on_current_runtime(0.0);
+        let queue = Arc::new(AnalyticsQueue::new(None, Some(2)));
+
+        let flushes = Arc::new(AtomicUsize::new(0));
+        {
+            let queue = queue.clone();
+            let flushes = flushes.clone();
+            scheduler.run_on(
+                move || {
+                    let queue = queue.clone();
+                    async move { queue.flush_requested().await }
+                },
+                move || {
+                    flushes.fetch_add(1, Ordering::SeqCst);
+                    async {}
+                },
+            );
+        }
+
+        let revenue = || Revenue {
+            id: Uuid::new_v4(),
+            recorded: 0,
+            project_id: 1,
+            amount: 1.0,
+        };
+
+        queue.add_revenue(revenue()).await;
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(flushes.load(Ordering::SeqCst), 0);
+
+        queue.add_revenue(revenue()).await;
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
     }
 }
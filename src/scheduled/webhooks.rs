@@ -0,0 +1,138 @@
+use crate::models::webhooks::Webhook;
+use crate::routes::query::project_totals;
+use crate::util::time;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde_json::json;
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Signs each payload as `sha256=<hex HMAC of the body>`, keyed by the webhook's secret
+pub const SIGNATURE_HEADER: &str = "Modrinth-Signature";
+
+// Deliveries are attempted hourly, so this disables a webhook after about a day of failures
+const MAX_FAILURES: u32 = 24;
+// Attempts per delivery, waiting twice as long after each failure
+const ATTEMPTS: u32 = 3;
+const FIRST_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The current state of every project's webhook, including disabled ones
+pub async fn current(
+    client: &clickhouse::Client,
+) -> Result<Vec<Webhook>, clickhouse::error::Error> {
+    client
+        .query(
+            r#"
+            SELECT id, recorded, project_id, url, secret, enabled, failures, last_sent
+            FROM webhooks
+            ORDER BY recorded DESC
+            LIMIT 1 BY project_id
+            "#,
+        )
+        .fetch_all::<Webhook>()
+        .await
+}
+
+/// Records a new state of a webhook
+pub async fn save(
+    client: &clickhouse::Client,
+    webhook: &Webhook,
+) -> Result<(), clickhouse::error::Error> {
+    let mut insert = client.insert("webhooks")?;
+    insert.write(webhook).await?;
+    insert.end().await
+}
+
+/// Posts yesterday's (UTC) downloads and views to every enabled webhook that wasn't sent them yet.
+/// Meant to run hourly: a delivery that still fails after its retries is tried again next run,
+/// until the webhook is disabled after `MAX_FAILURES` failed runs in a row.
+pub async fn deliver_summaries(
+    client: &clickhouse::Client,
+) -> Result<(), clickhouse::error::Error> {
+    let end = Utc::now().date().and_hms(0, 0, 0);
+    let start = end - Duration::days(1);
+    let day_start = time::to_record_ts(start);
+
+    let http = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .unwrap();
+
+    for webhook in current(client).await? {
+        if !webhook.enabled || webhook.last_sent >= day_start {
+            continue;
+        }
+
+        let totals = project_totals(client, webhook.project_id, Some((start, end)), false).await?;
+
+        let body = json!({
+            "project_id": webhook.project_id.to_string(),
+            "date": start.format("%Y-%m-%d").to_string(),
+            "downloads": totals.downloads,
+            "views": totals.views,
+        })
+        .to_string();
+
+        let delivered = deliver(&http, &webhook, body).await;
+
+        let mut state = Webhook {
+            id: Uuid::new_v4(),
+            recorded: time::record_now(),
+            ..webhook
+        };
+        if delivered {
+            state.failures = 0;
+            state.last_sent = day_start;
+        } else {
+            state.failures += 1;
+            if state.failures >= MAX_FAILURES {
+                warn!(
+                    "Disabling the webhook of project {} after {} failed deliveries",
+                    state.project_id, state.failures
+                );
+                state.enabled = false;
+            }
+        }
+
+        save(client, &state).await?;
+    }
+
+    Ok(())
+}
+
+async fn deliver(http: &reqwest::Client, webhook: &Webhook, body: String) -> bool {
+    let mut mac = match Hmac::<Sha256>::new_from_slice(webhook.secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body.as_bytes());
+    let signature = format!("sha256={:x}", mac.finalize().into_bytes());
+
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 1..=ATTEMPTS {
+        let result = http
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => return true,
+            Err(e) => debug!(
+                "Delivering to the webhook of project {} failed (attempt {}): {}",
+                webhook.project_id, attempt, e
+            ),
+        }
+
+        if attempt < ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
+    false
+}
@@ -1,11 +1,18 @@
 use crate::routes::ApiError;
+use crate::util::cache::TtlCache;
+use crate::util::config::Config;
+use crate::util::labrinth;
+use crate::util::limiter::ConcurrencyLimiter;
+use crate::util::metrics::Metrics;
 use actix_web::http::header::HeaderMap;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Deserialize)]
 pub struct User {
     pub id: String,
-    pub username: String,
     pub role: Role,
 }
 
@@ -19,56 +26,109 @@ pub enum Role {
 
 #[derive(Deserialize)]
 pub struct Team {
-    pub id: String,
     pub members: Vec<TeamMember>,
 }
 
 #[derive(Deserialize)]
 pub struct TeamMember {
-    pub team_id: String,
     pub user: User,
-    pub role: String,
     pub permissions: u32,
     pub accepted: bool,
 }
 
-pub async fn check_is_authorized(
-    project_id: Option<&str>,
-    headers: &HeaderMap,
-    use_payouts_permission: bool,
-) -> Result<(), ApiError> {
-    let token = headers
-        .get("Authorization")
-        .ok_or_else(|| ApiError::Authentication("missing 'Authorization' header".to_string()))?
-        .to_str()
-        .map_err(|_| ApiError::Authentication("invalid 'Authorization' header".to_string()))?;
-
-    let client = reqwest::Client::new();
-
-    let user: User = client
-        .get(format!("{}user", dotenvy::var("LABRINTH_API_URL")?))
-        .header("x-ratelimit-key", dotenvy::var("LABRINTH_RATE_LIMIT_KEY")?)
-        .header("Authorization", token)
-        .send()
-        .await?
-        .json()
-        .await?;
-
-    if user.role != Role::Admin {
-        if let Some(project_id) = project_id {
-            let members: Team = client
-                .get(format!(
-                    "{}project/{}/members",
-                    dotenvy::var("LABRINTH_API_URL")?,
-                    project_id
-                ))
-                .header("x-ratelimit-key", dotenvy::var("LABRINTH_RATE_LIMIT_KEY")?)
-                .header("Authorization", token)
-                .send()
-                .await?
-                .json()
-                .await?;
+/// Checks requesters may view project analytics, by looking them up in labrinth. Lookups share a
+/// concurrency limit, so a burst of dashboard requests queues briefly instead of tripping
+/// labrinth's rate limit. Decisions are cached briefly, as dashboards poll the same projects.
+pub struct Authorizer {
+    // (SHA-256 of the token, project ID or slug, whether payouts were checked) -> allowed
+    decisions: TtlCache<([u8; 32], Option<String>, bool), bool>,
+    limiter: ConcurrencyLimiter,
+    client: reqwest::Client,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+}
+
+impl Authorizer {
+    pub fn new(
+        ttl: Duration,
+        limiter: ConcurrencyLimiter,
+        client: reqwest::Client,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Authorizer {
+            decisions: TtlCache::new(ttl),
+            limiter,
+            client,
+            config,
+            metrics,
+        }
+    }
+
+    pub async fn check(
+        &self,
+        project_id: Option<&str>,
+        headers: &HeaderMap,
+        use_payouts_permission: bool,
+    ) -> Result<(), ApiError> {
+        self.check_all(project_id.as_slice(), headers, use_payouts_permission)
+            .await
+    }
+
+    /// Checks the requester may view the analytics of every one of the projects. The user is only
+    /// looked up once, and the teams are fetched concurrently.
+    pub async fn check_all(
+        &self,
+        project_ids: &[&str],
+        headers: &HeaderMap,
+        use_payouts_permission: bool,
+    ) -> Result<(), ApiError> {
+        let token = headers
+            .get("Authorization")
+            .ok_or_else(|| ApiError::Authentication("missing 'Authorization' header".to_string()))?
+            .to_str()
+            .map_err(|_| ApiError::Authentication("invalid 'Authorization' header".to_string()))?;
+
+        let token_hash: [u8; 32] = Sha256::digest(token).into();
+        let keys = if project_ids.is_empty() {
+            vec![(token_hash, None, use_payouts_permission)]
+        } else {
+            project_ids
+                .iter()
+                .map(|x| (token_hash, Some(x.to_string()), use_payouts_permission))
+                .collect()
+        };
+
+        let cached = keys
+            .iter()
+            .map(|key| self.decisions.get(key))
+            .collect::<Option<Vec<_>>>();
+        if let Some(decisions) = cached {
+            return if decisions.into_iter().all(|x| x) {
+                Ok(())
+            } else {
+                Err(denied(project_ids))
+            };
+        }
+
+        // Held for the whole check, so each check counts once however many projects it covers
+        let _permit = self
+            .limiter
+            .acquire()
+            .await
+            .ok_or(ApiError::RateLimited { retry_after: 1 })?;
+
+        let client = &self.client;
+        let config = &*self.config;
+        let metrics = &*self.metrics;
+
+        let user = get_user(client, config, metrics, token).await?;
 
+        let allowed = if user.role == Role::Admin {
+            vec![true; keys.len()]
+        } else if project_ids.is_empty() {
+            vec![false]
+        } else {
             const VIEW_ANALYTICS: u32 = 1 << 8;
             const VIEW_PAYOUTS: u32 = 1 << 9;
 
@@ -78,23 +138,224 @@ pub async fn check_is_authorized(
                 VIEW_ANALYTICS
             };
 
-            members
-                .members
+            let teams = futures::future::try_join_all(
+                project_ids
+                    .iter()
+                    .map(|project_id| get_team(client, config, metrics, project_id, Some(token))),
+            )
+            .await?;
+
+            teams
                 .iter()
-                .find(|x| {
-                    x.user.id == user.id && x.accepted && (x.permissions & permission) == permission
+                .map(|team| {
+                    team.members.iter().any(|x| {
+                        x.user.id == user.id
+                            && x.accepted
+                            && (x.permissions & permission) == permission
+                    })
                 })
-                .ok_or_else(|| {
-                    ApiError::Authentication(
-                        "You are not allowed to view analytics from this team!".to_string(),
-                    )
-                })?;
+                .collect()
+        };
+
+        for (key, allowed) in keys.into_iter().zip(&allowed) {
+            self.decisions.insert(key, *allowed);
+        }
+
+        if allowed.into_iter().all(|x| x) {
+            Ok(())
         } else {
-            return Err(ApiError::Authentication(
-                "Please specify a project ID".to_string(),
-            ));
+            Err(denied(project_ids))
         }
     }
 
-    Ok(())
+    pub fn prune(&self) {
+        self.decisions.prune();
+    }
+}
+
+fn denied(project_ids: &[&str]) -> ApiError {
+    if project_ids.is_empty() {
+        ApiError::Authentication("Please specify a project ID".to_string())
+    } else {
+        ApiError::Authentication(
+            "You are not allowed to view analytics from this team!".to_string(),
+        )
+    }
+}
+
+/// Retrieves the user an authorization token belongs to
+pub async fn get_user(
+    client: &reqwest::Client,
+    config: &Config,
+    metrics: &Metrics,
+    token: &str,
+) -> Result<User, ApiError> {
+    let request = client
+        .get(format!("{}user", config.labrinth_url()?))
+        .header("x-ratelimit-key", &config.labrinth_rate_limit_key)
+        .header("Authorization", token);
+
+    Ok(labrinth::send(config, metrics, "user", request)
+        .await?
+        .json()
+        .await?)
+}
+
+/// Retrieves the team of a project by ID or slug. Without a token, only the members visible to
+/// the public are included.
+pub async fn get_team(
+    client: &reqwest::Client,
+    config: &Config,
+    metrics: &Metrics,
+    project: &str,
+    token: Option<&str>,
+) -> Result<Team, ApiError> {
+    let mut request = client
+        .get(format!(
+            "{}project/{}/members",
+            config.labrinth_url()?,
+            project
+        ))
+        .header("x-ratelimit-key", &config.labrinth_rate_limit_key);
+
+    if let Some(token) = token {
+        request = request.header("Authorization", token);
+    }
+
+    Ok(labrinth::send(config, metrics, "project_members", request)
+        .await?
+        .json()
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::tests::config_from;
+    use actix_web::http::header::{HeaderValue, AUTHORIZATION};
+    use serde_json::json;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const VIEW_ANALYTICS: u32 = 1 << 8;
+
+    fn authorizer(labrinth: &MockServer) -> Authorizer {
+        let (config, _) = config_from(&[
+            ("LABRINTH_API_URL", &format!("{}/", labrinth.uri())),
+            ("LABRINTH_RATE_LIMIT_KEY", "key"),
+        ]);
+
+        Authorizer::new(
+            Duration::from_secs(60),
+            ConcurrencyLimiter::new(1, Duration::from_secs(1)),
+            reqwest::Client::new(),
+            Arc::new(config),
+            Arc::new(Metrics::new().unwrap()),
+        )
+    }
+
+    fn headers(token: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static(token));
+        headers
+    }
+
+    /// Serves `token` as a developer with the same ID, expecting it to be looked up `times` times
+    async fn mount_user(labrinth: &MockServer, token: &str, times: u64) {
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .and(header("Authorization", token))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "id": token, "role": "developer" })),
+            )
+            .expect(times)
+            .mount(labrinth)
+            .await;
+    }
+
+    /// Serves the team of `project` with `alice` as its only member, holding `permissions`
+    async fn mount_team(labrinth: &MockServer, project: &str, permissions: u32, times: u64) {
+        Mock::given(method("GET"))
+            .and(path(format!("/project/{project}/members")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "members": [{
+                    "user": { "id": "alice", "role": "developer" },
+                    "permissions": permissions,
+                    "accepted": true,
+                }],
+            })))
+            .expect(times)
+            .mount(labrinth)
+            .await;
+    }
+
+    #[actix_rt::test]
+    async fn caches_decisions_per_token() {
+        let labrinth = MockServer::start().await;
+        mount_user(&labrinth, "alice", 1).await;
+        mount_user(&labrinth, "bob", 1).await;
+        mount_team(&labrinth, "sodium", VIEW_ANALYTICS, 2).await;
+        let authorizer = authorizer(&labrinth);
+
+        assert!(authorizer
+            .check(Some("sodium"), &headers("alice"), false)
+            .await
+            .is_ok());
+        assert!(authorizer
+            .check(Some("sodium"), &headers("alice"), false)
+            .await
+            .is_ok());
+
+        // Alice's cached decision doesn't carry over to another token
+        assert!(authorizer
+            .check(Some("sodium"), &headers("bob"), false)
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn caches_decisions_per_project() {
+        let labrinth = MockServer::start().await;
+        mount_user(&labrinth, "alice", 2).await;
+        mount_team(&labrinth, "sodium", VIEW_ANALYTICS, 1).await;
+        mount_team(&labrinth, "lithium", 0, 1).await;
+        let authorizer = authorizer(&labrinth);
+
+        assert!(authorizer
+            .check(Some("sodium"), &headers("alice"), false)
+            .await
+            .is_ok());
+        assert!(authorizer
+            .check(Some("lithium"), &headers("alice"), false)
+            .await
+            .is_err());
+        assert!(authorizer
+            .check(Some("lithium"), &headers("alice"), false)
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn caches_payouts_decisions_separately() {
+        let labrinth = MockServer::start().await;
+        mount_user(&labrinth, "alice", 2).await;
+        mount_team(&labrinth, "sodium", VIEW_ANALYTICS, 2).await;
+        let authorizer = authorizer(&labrinth);
+
+        assert!(authorizer
+            .check(Some("sodium"), &headers("alice"), false)
+            .await
+            .is_ok());
+
+        // Viewing analytics doesn't grant viewing payouts, cached or not
+        assert!(authorizer
+            .check(Some("sodium"), &headers("alice"), true)
+            .await
+            .is_err());
+        assert!(authorizer
+            .check(Some("sodium"), &headers("alice"), true)
+            .await
+            .is_err());
+    }
 }
@@ -0,0 +1,36 @@
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Caches slowly changing query results for a fixed time
+pub struct TtlCache<K, V> {
+    entries: DashMap<K, (V, Instant)>,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        TtlCache {
+            entries: DashMap::new(),
+            ttl,
+        }
+    }
+
+    /// Returns the cached value if it was inserted within the TTL
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.1.elapsed() < self.ttl)
+            .map(|entry| entry.0.clone())
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.entries.insert(key, (value, Instant::now()));
+    }
+
+    /// Removes every entry older than the TTL
+    pub fn prune(&self) {
+        self.entries
+            .retain(|_, (_, inserted)| inserted.elapsed() < self.ttl);
+    }
+}
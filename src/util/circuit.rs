@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Sheds ingest load once the analytics queue has failed to flush `threshold` times in a row, so
+/// callers get back-pressure instead of events piling up in memory while storage is down. It
+/// closes again after the next successful flush.
+pub struct StorageCircuit {
+    consecutive_failures: AtomicU32,
+    threshold: u32,
+    // How many downloads may be queued while the circuit is open
+    download_cap: usize,
+}
+
+impl StorageCircuit {
+    pub fn new(threshold: u32, download_cap: usize) -> Self {
+        StorageCircuit {
+            consecutive_failures: AtomicU32::new(0),
+            threshold: threshold.max(1),
+            download_cap,
+        }
+    }
+
+    pub fn record_flush(&self, succeeded: bool) {
+        if succeeded {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+        } else {
+            self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether ingest should currently be shed
+    pub fn is_open(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) >= self.threshold
+    }
+
+    /// Whether another download may be queued on top of the `queued` ones. Downloads are
+    /// internal and more valuable than views, so they are still buffered up to a cap while the
+    /// circuit is open.
+    pub fn accepts_download(&self, queued: usize) -> bool {
+        !self.is_open() || queued < self.download_cap
+    }
+}
@@ -0,0 +1,616 @@
+use crate::routes::ApiError;
+use crate::util::cors::AllowedOrigins;
+use crate::util::env::{parse_strings_from_var, ConfigReport};
+use crate::util::proxies::TrustedProxies;
+use log::warn;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Short enough to guess would let IP hashes be reversed by trying every IP
+const MIN_PEPPER_LENGTH: usize = 32;
+
+/// Every setting read from the environment. They are parsed once at startup, so a missing or
+/// malformed variable is reported before serving rather than by the first request needing it.
+pub struct Config {
+    pub bind_addr: String,
+    // Accepted admin keys, several while they are being rotated
+    pub admin_keys: Vec<String>,
+
+    pub clickhouse_url: String,
+    pub clickhouse_user: String,
+    pub clickhouse_password: String,
+    pub clickhouse_database: String,
+
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allow_subdomains: bool,
+    pub trusted_proxies: Vec<String>,
+    pub proxy_secret: Option<String>,
+    pub prefetch_headers: Option<Vec<String>>,
+    pub site_path_segments: Option<usize>,
+    pub store_full_path: bool,
+
+    // Without it, views are recorded unattributed and project queries fail
+    pub labrinth_api_url: Option<String>,
+    pub labrinth_rate_limit_key: String,
+    // Attempts made at a labrinth request, counting the first
+    pub labrinth_retry_attempts: u32,
+    // Delay before the first retry, doubling after each
+    pub labrinth_retry_base: Duration,
+    pub labrinth_timeout: Duration,
+    pub labrinth_max_in_flight: usize,
+    pub labrinth_queue_timeout: Duration,
+    pub labrinth_auth_max_in_flight: usize,
+    pub labrinth_auth_queue_timeout: Duration,
+
+    // A local database is used as is, otherwise GeoLite2 is downloaded with the license key
+    pub maxmind_local_db: Option<PathBuf>,
+    pub maxmind_license_key: String,
+    pub maxmind_db_path: Option<PathBuf>,
+    pub maxmind_enable_city: bool,
+
+    pub verify_download_ownership: bool,
+    pub download_batch_max_size: usize,
+    // Labrinth lookups v1/downloads makes at once
+    pub download_batch_concurrency: usize,
+    // Lowercase hosts downloads may be served from, any if empty
+    pub download_allowed_hosts: Vec<String>,
+    pub download_session_timeout: Duration,
+    pub download_min_interval: Option<Duration>,
+    pub download_dedup_window: Option<Duration>,
+
+    pub storage_failure_threshold: u32,
+    pub storage_down_download_cap: usize,
+    pub queue_max_entries: Option<usize>,
+    pub queue_flush_threshold: Option<usize>,
+    pub queue_persist_path: Option<PathBuf>,
+    pub scheduler_jitter: f64,
+
+    pub redis_url: Option<String>,
+    pub rate_limit_pepper: String,
+    pub rate_limit_max_views: u32,
+
+    // `nats` or `kafka`, with events not published if unset
+    pub event_broker: Option<String>,
+    pub event_broker_url: Option<String>,
+    pub event_topic_prefix: String,
+
+    pub audit_multipliers: bool,
+    // Posts daily summaries to registered webhooks, off until receivers are ready for them
+    pub webhook_delivery: bool,
+}
+
+impl Config {
+    /// Reads the settings, adding any problems with them to the report. Settings with problems
+    /// are left at their defaults.
+    pub fn from_env(report: &mut ConfigReport) -> Self {
+        // `ARIADNE_ADMIN_KEYS` (a JSON array) lets several keys be valid at once so they can be
+        // rotated without downtime, falling back to the single `ARIADNE_ADMIN_KEY`
+        let admin_keys = if dotenvy::var("ARIADNE_ADMIN_KEYS").is_ok() {
+            let keys = parse_strings_from_var("ARIADNE_ADMIN_KEYS");
+            if keys.is_none() {
+                report.problem("`ARIADNE_ADMIN_KEYS` is not a json array of strings".to_string());
+            }
+            keys
+        } else {
+            Some(dotenvy::var("ARIADNE_ADMIN_KEY").into_iter().collect())
+        }
+        .map(|keys| {
+            keys.into_iter()
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>()
+        });
+        if admin_keys.as_ref().is_some_and(Vec::is_empty) {
+            report.problem(
+                "`ARIADNE_ADMIN_KEYS` or `ARIADNE_ADMIN_KEY` must set an admin key".to_string(),
+            );
+        }
+
+        let cors_allowed_origins = match parse_strings_from_var("CORS_ALLOWED_ORIGINS") {
+            Some(origins) => {
+                for origin in origins.iter().filter(|x| !AllowedOrigins::is_valid(x)) {
+                    report.problem(format!(
+                        "Origin `{origin}` in `CORS_ALLOWED_ORIGINS` is not a valid origin"
+                    ));
+                }
+                origins
+            }
+            None => {
+                report.problem(
+                    "`CORS_ALLOWED_ORIGINS` is missing or not a json array of strings".to_string(),
+                );
+                Vec::new()
+            }
+        };
+
+        let trusted_proxies = if dotenvy::var("TRUSTED_PROXIES").is_ok() {
+            match parse_strings_from_var("TRUSTED_PROXIES") {
+                Some(ranges) => {
+                    for range in ranges.iter().filter(|x| !TrustedProxies::is_valid(x)) {
+                        report.problem(format!(
+                            "Range `{range}` in `TRUSTED_PROXIES` is not a valid CIDR range"
+                        ));
+                    }
+                    ranges
+                }
+                None => {
+                    report.problem("`TRUSTED_PROXIES` is not a json array of strings".to_string());
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+
+        let prefetch_headers = if dotenvy::var("PREFETCH_HEADERS").is_ok() {
+            let headers = parse_strings_from_var("PREFETCH_HEADERS");
+            if headers.is_none() {
+                report.problem("`PREFETCH_HEADERS` is not a json array of strings".to_string());
+            }
+            headers
+        } else {
+            None
+        };
+
+        // Only needed for attribution and authenticated queries, so it doesn't block startup
+        let labrinth_api_url = report.optional::<String>("LABRINTH_API_URL");
+        if labrinth_api_url.is_none() {
+            warn!(
+                "`LABRINTH_API_URL` is missing, views won't be attributed and project queries will fail"
+            );
+        }
+
+        // Identifies ariadne to labrinth's rate limiter, so it's only needed alongside labrinth
+        let labrinth_rate_limit_key =
+            report.optional_where::<String>("LABRINTH_RATE_LIMIT_KEY", "must not be empty", |x| {
+                !x.is_empty()
+            });
+        if labrinth_api_url.is_some() && dotenvy::var("LABRINTH_RATE_LIMIT_KEY").is_err() {
+            report.problem("`LABRINTH_API_URL` requires `LABRINTH_RATE_LIMIT_KEY`".to_string());
+        }
+
+        let maxmind_local_db =
+            report.optional_where::<PathBuf>("MAXMIND_LOCAL_DB", "must be an existing file", |x| {
+                x.is_file()
+            });
+        let maxmind_license_key = report.optional::<String>("MAXMIND_LICENSE_KEY");
+        if dotenvy::var("MAXMIND_LOCAL_DB").is_err() && maxmind_license_key.is_none() {
+            report.problem("`MAXMIND_LICENSE_KEY` or `MAXMIND_LOCAL_DB` must be set".to_string());
+        }
+
+        let verify_download_ownership = report
+            .optional::<bool>("VERIFY_DOWNLOAD_OWNERSHIP")
+            .unwrap_or(false);
+        if verify_download_ownership && labrinth_api_url.is_none() {
+            report.problem("`VERIFY_DOWNLOAD_OWNERSHIP` requires `LABRINTH_API_URL`".to_string());
+        }
+
+        let download_allowed_hosts = if dotenvy::var("DOWNLOAD_ALLOWED_HOSTS").is_ok() {
+            let hosts = parse_strings_from_var("DOWNLOAD_ALLOWED_HOSTS");
+            if hosts.is_none() {
+                report
+                    .problem("`DOWNLOAD_ALLOWED_HOSTS` is not a json array of strings".to_string());
+            }
+            hosts.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        let redis_url = report.optional::<String>("REDIS_URL");
+        if let Some(url) = &redis_url {
+            if redis::Client::open(url.as_str()).is_err() {
+                report.problem("`REDIS_URL` is not a valid Redis URL".to_string());
+            }
+        }
+
+        let rate_limit_pepper = report.required::<String>("RATE_LIMIT_PEPPER");
+        if rate_limit_pepper
+            .as_ref()
+            .is_some_and(|x| x.len() < MIN_PEPPER_LENGTH)
+        {
+            report.problem(format!(
+                "`RATE_LIMIT_PEPPER` must be at least {MIN_PEPPER_LENGTH} characters"
+            ));
+        }
+        let rate_limit_pepper = rate_limit_pepper.unwrap_or_default();
+
+        let event_broker = report.optional::<String>("EVENT_BROKER");
+        let event_broker_url = match event_broker.as_deref() {
+            Some("nats") | Some("kafka") => report.required::<String>("EVENT_BROKER_URL"),
+            Some(broker) => {
+                report.problem(format!(
+                    "`EVENT_BROKER` must be `nats` or `kafka`, found `{broker}`"
+                ));
+                None
+            }
+            None => None,
+        };
+
+        Config {
+            bind_addr: report.required("BIND_ADDR").unwrap_or_default(),
+            admin_keys: admin_keys.unwrap_or_default(),
+
+            clickhouse_url: report.required("CLICKHOUSE_URL").unwrap_or_default(),
+            clickhouse_user: report.required("CLICKHOUSE_USER").unwrap_or_default(),
+            clickhouse_password: report.required("CLICKHOUSE_PASSWORD").unwrap_or_default(),
+            clickhouse_database: report.required("CLICKHOUSE_DATABASE").unwrap_or_default(),
+
+            cors_allowed_origins,
+            cors_allow_subdomains: report.optional("CORS_ALLOW_SUBDOMAINS").unwrap_or(false),
+            trusted_proxies,
+            proxy_secret: report
+                .optional::<String>("PROXY_SECRET")
+                .filter(|x| !x.is_empty()),
+            prefetch_headers,
+            site_path_segments: report.optional_where(
+                "SITE_PATH_SEGMENTS",
+                "must be at least 1",
+                |x| *x > 0,
+            ),
+            store_full_path: report.optional("STORE_FULL_PATH").unwrap_or(false),
+
+            labrinth_api_url,
+            labrinth_rate_limit_key: labrinth_rate_limit_key.unwrap_or_default(),
+            labrinth_retry_attempts: report
+                .optional_where("LABRINTH_RETRY_ATTEMPTS", "must be at least 1", |x| *x > 0)
+                .unwrap_or(3),
+            labrinth_retry_base: Duration::from_millis(
+                report.optional("LABRINTH_RETRY_BASE_MS").unwrap_or(100),
+            ),
+            labrinth_timeout: Duration::from_millis(
+                report
+                    .optional_where("LABRINTH_TIMEOUT_MS", "must be at least 1", |x| *x > 0)
+                    .unwrap_or(5000),
+            ),
+            labrinth_max_in_flight: report
+                .optional_where("LABRINTH_MAX_IN_FLIGHT", "must be at least 1", |x| *x > 0)
+                .unwrap_or(64),
+            labrinth_queue_timeout: Duration::from_millis(
+                report.optional("LABRINTH_QUEUE_TIMEOUT_MS").unwrap_or(500),
+            ),
+            labrinth_auth_max_in_flight: report
+                .optional_where("LABRINTH_AUTH_MAX_IN_FLIGHT", "must be at least 1", |x| {
+                    *x > 0
+                })
+                .unwrap_or(16),
+            labrinth_auth_queue_timeout: Duration::from_millis(
+                report
+                    .optional("LABRINTH_AUTH_QUEUE_TIMEOUT_MS")
+                    .unwrap_or(5000),
+            ),
+
+            maxmind_local_db,
+            maxmind_license_key: maxmind_license_key.unwrap_or_default(),
+            maxmind_db_path: report.optional("MAXMIND_DB_PATH"),
+            maxmind_enable_city: report.optional("MAXMIND_ENABLE_CITY").unwrap_or(false),
+
+            verify_download_ownership,
+            download_batch_max_size: report.optional("DOWNLOAD_BATCH_MAX_SIZE").unwrap_or(100),
+            download_batch_concurrency: report
+                .optional_where("DOWNLOAD_BATCH_CONCURRENCY", "must be at least 1", |x| {
+                    *x > 0
+                })
+                .unwrap_or(8),
+            download_allowed_hosts: download_allowed_hosts
+                .iter()
+                .map(|x| x.to_lowercase())
+                .collect(),
+            download_session_timeout: Duration::from_secs(
+                report
+                    .optional_where("DOWNLOAD_SESSION_TIMEOUT_SECS", "must be at least 1", |x| {
+                        *x > 0
+                    })
+                    .unwrap_or(60 * 60),
+            ),
+            // Off by default, as downloads through shared CDN or proxy IPs would otherwise be
+            // undercounted
+            download_min_interval: report
+                .optional("DOWNLOAD_MIN_INTERVAL_SECS")
+                .map(Duration::from_secs),
+            download_dedup_window: report
+                .optional("DOWNLOAD_DEDUP_WINDOW_SECS")
+                .map(Duration::from_secs),
+
+            storage_failure_threshold: report
+                .optional_where("STORAGE_FAILURE_THRESHOLD", "must be at least 1", |x| {
+                    *x > 0
+                })
+                .unwrap_or(3),
+            storage_down_download_cap: report
+                .optional("STORAGE_DOWN_DOWNLOAD_CAP")
+                .unwrap_or(100_000),
+            queue_max_entries: report.optional_where(
+                "QUEUE_MAX_ENTRIES",
+                "must be at least 1",
+                |x| *x > 0,
+            ),
+            queue_flush_threshold: report.optional_where(
+                "QUEUE_FLUSH_THRESHOLD",
+                "must be at least 1",
+                |x| *x > 0,
+            ),
+            queue_persist_path: report.optional("QUEUE_PERSIST_PATH"),
+            scheduler_jitter: report
+                .optional_where("SCHEDULER_JITTER", "must be between 0 and 1", |x| {
+                    (0.0..=1.0).contains(x)
+                })
+                .unwrap_or(0.0),
+
+            redis_url,
+            rate_limit_pepper,
+            rate_limit_max_views: report
+                .optional_where("RATE_LIMIT_MAX_VIEWS", "must be at least 1", |x| *x > 0)
+                .unwrap_or(5),
+
+            event_broker,
+            event_broker_url,
+            event_topic_prefix: report
+                .optional("EVENT_TOPIC_PREFIX")
+                .unwrap_or_else(|| "ariadne".to_string()),
+
+            audit_multipliers: report.optional("AUDIT_MULTIPLIERS").unwrap_or(false),
+            webhook_delivery: report.optional("WEBHOOK_DELIVERY").unwrap_or(false),
+        }
+    }
+
+    /// Whether downloads may be served from the host, which is always the case without an
+    /// allowlist
+    pub fn allows_download_host(&self, host: &str) -> bool {
+        self.download_allowed_hosts.is_empty()
+            || self
+                .download_allowed_hosts
+                .iter()
+                .any(|x| x.eq_ignore_ascii_case(host))
+    }
+
+    /// The labrinth API URL, for lookups that can't do without it
+    pub fn labrinth_url(&self) -> Result<&str, ApiError> {
+        self.labrinth_api_url
+            .as_deref()
+            .ok_or(ApiError::Env(dotenvy::Error::EnvVar(
+                std::env::VarError::NotPresent,
+            )))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Tests share the process environment, so only one may set it up at a time
+    static ENV: Mutex<()> = Mutex::new(());
+
+    const VARS: &[&str] = &[
+        "BIND_ADDR",
+        "ARIADNE_ADMIN_KEYS",
+        "ARIADNE_ADMIN_KEY",
+        "CLICKHOUSE_URL",
+        "CLICKHOUSE_USER",
+        "CLICKHOUSE_PASSWORD",
+        "CLICKHOUSE_DATABASE",
+        "CORS_ALLOWED_ORIGINS",
+        "CORS_ALLOW_SUBDOMAINS",
+        "TRUSTED_PROXIES",
+        "PROXY_SECRET",
+        "PREFETCH_HEADERS",
+        "SITE_PATH_SEGMENTS",
+        "STORE_FULL_PATH",
+        "LABRINTH_API_URL",
+        "LABRINTH_RATE_LIMIT_KEY",
+        "LABRINTH_RETRY_ATTEMPTS",
+        "LABRINTH_RETRY_BASE_MS",
+        "LABRINTH_TIMEOUT_MS",
+        "LABRINTH_MAX_IN_FLIGHT",
+        "LABRINTH_QUEUE_TIMEOUT_MS",
+        "LABRINTH_AUTH_MAX_IN_FLIGHT",
+        "LABRINTH_AUTH_QUEUE_TIMEOUT_MS",
+        "MAXMIND_LOCAL_DB",
+        "MAXMIND_LICENSE_KEY",
+        "MAXMIND_DB_PATH",
+        "MAXMIND_ENABLE_CITY",
+        "VERIFY_DOWNLOAD_OWNERSHIP",
+        "DOWNLOAD_BATCH_MAX_SIZE",
+        "DOWNLOAD_BATCH_CONCURRENCY",
+        "DOWNLOAD_ALLOWED_HOSTS",
+        "DOWNLOAD_SESSION_TIMEOUT_SECS",
+        "DOWNLOAD_MIN_INTERVAL_SECS",
+        "DOWNLOAD_DEDUP_WINDOW_SECS",
+        "STORAGE_FAILURE_THRESHOLD",
+        "STORAGE_DOWN_DOWNLOAD_CAP",
+        "QUEUE_MAX_ENTRIES",
+        "QUEUE_FLUSH_THRESHOLD",
+        "QUEUE_PERSIST_PATH",
+        "SCHEDULER_JITTER",
+        "REDIS_URL",
+        "RATE_LIMIT_PEPPER",
+        "RATE_LIMIT_MAX_VIEWS",
+        "EVENT_BROKER",
+        "EVENT_BROKER_URL",
+        "EVENT_TOPIC_PREFIX",
+        "AUDIT_MULTIPLIERS",
+        "WEBHOOK_DELIVERY",
+    ];
+
+    // The smallest environment that passes the checks
+    const MINIMAL: &[(&str, &str)] = &[
+        ("BIND_ADDR", "0.0.0.0:8000"),
+        ("ARIADNE_ADMIN_KEY", "key"),
+        ("CLICKHOUSE_URL", "http://localhost:8123"),
+        ("CLICKHOUSE_USER", "default"),
+        ("CLICKHOUSE_PASSWORD", ""),
+        ("CLICKHOUSE_DATABASE", "staging_ariadne"),
+        ("CORS_ALLOWED_ORIGINS", r#"["https://modrinth.com"]"#),
+        ("MAXMIND_LICENSE_KEY", "key"),
+        ("RATE_LIMIT_PEPPER", "feedbeeffeedbeeffeedbeeffeedbeef"),
+    ];
+
+    /// Reads the config from exactly the given variables, on top of `MINIMAL` unless a variable
+    /// is overridden there. An empty value leaves the variable unset.
+    pub(crate) fn config_from(vars: &[(&str, &str)]) -> (Config, ConfigReport) {
+        let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Loads `.env` now, so it can't set anything after the variables are cleared
+        let _ = dotenvy::var("BIND_ADDR");
+        for var in VARS {
+            std::env::remove_var(var);
+        }
+        for (var, value) in MINIMAL.iter().chain(vars) {
+            if value.is_empty() && vars.iter().any(|(x, _)| x == var) {
+                std::env::remove_var(var);
+            } else {
+                std::env::set_var(var, value);
+            }
+        }
+
+        let mut report = ConfigReport::default();
+        let config = Config::from_env(&mut report);
+
+        for var in VARS {
+            std::env::remove_var(var);
+        }
+
+        (config, report)
+    }
+
+    #[test]
+    fn minimal_env_uses_defaults() {
+        let (config, report) = config_from(&[]);
+
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(config.bind_addr, "0.0.0.0:8000");
+        assert_eq!(config.clickhouse_password, "");
+        assert_eq!(config.labrinth_api_url, None);
+        assert_eq!(config.labrinth_retry_attempts, 3);
+        assert_eq!(config.labrinth_timeout, Duration::from_millis(5000));
+        assert_eq!(
+            config.download_session_timeout,
+            Duration::from_secs(60 * 60)
+        );
+        assert_eq!(config.download_min_interval, None);
+        assert_eq!(config.rate_limit_max_views, 5);
+        assert_eq!(config.event_topic_prefix, "ariadne");
+        assert!(config.download_allowed_hosts.is_empty());
+        assert_eq!(config.download_batch_concurrency, 8);
+        assert!(!config.webhook_delivery);
+    }
+
+    #[test]
+    fn reads_set_values() {
+        let (config, report) = config_from(&[
+            ("LABRINTH_API_URL", "https://api.modrinth.com/v2/"),
+            ("LABRINTH_RATE_LIMIT_KEY", "key"),
+            ("DOWNLOAD_MIN_INTERVAL_SECS", "30"),
+            ("DOWNLOAD_ALLOWED_HOSTS", r#"["CDN.modrinth.com"]"#),
+            ("SCHEDULER_JITTER", "0.25"),
+            ("EVENT_BROKER", "nats"),
+            ("EVENT_BROKER_URL", "nats://localhost:4222"),
+        ]);
+
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(
+            config.labrinth_url().ok(),
+            Some("https://api.modrinth.com/v2/")
+        );
+        assert_eq!(config.download_min_interval, Some(Duration::from_secs(30)));
+        assert!(config.allows_download_host("cdn.modrinth.com"));
+        assert!(!config.allows_download_host("example.com"));
+        assert_eq!(config.scheduler_jitter, 0.25);
+        assert_eq!(config.event_broker.as_deref(), Some("nats"));
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let (_, report) = config_from(&[
+            ("BIND_ADDR", ""),
+            ("CORS_ALLOWED_ORIGINS", r#"["https://modrinth.com/path"]"#),
+            ("LABRINTH_RETRY_ATTEMPTS", "0"),
+            ("SCHEDULER_JITTER", "2"),
+            ("EVENT_BROKER", "kafka"),
+        ]);
+        let report = report.to_string();
+
+        assert!(report.starts_with("found 5 problem(s)"), "{report}");
+        assert!(report.contains("`BIND_ADDR` is missing"));
+        assert!(report.contains("Origin `https://modrinth.com/path`"));
+        assert!(report.contains("`LABRINTH_RETRY_ATTEMPTS` must be at least 1"));
+        assert!(report.contains("`SCHEDULER_JITTER` must be between 0 and 1"));
+        assert!(report.contains("`EVENT_BROKER_URL` is missing"));
+    }
+
+    #[test]
+    fn requires_admin_key() {
+        let (config, report) = config_from(&[("ARIADNE_ADMIN_KEY", "")]);
+        assert!(!report.is_ok());
+        assert!(config.admin_keys.is_empty());
+
+        let (_, report) = config_from(&[("ARIADNE_ADMIN_KEYS", r#"["", ""]"#)]);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn reads_several_admin_keys() {
+        let (config, report) = config_from(&[
+            ("ARIADNE_ADMIN_KEY", ""),
+            ("ARIADNE_ADMIN_KEYS", r#"["old", "", "new"]"#),
+        ]);
+
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(config.admin_keys, ["old", "new"]);
+
+        // The list takes precedence over the single key
+        let (config, _) = config_from(&[("ARIADNE_ADMIN_KEYS", r#"["new"]"#)]);
+        assert_eq!(config.admin_keys, ["new"]);
+
+        let (_, report) = config_from(&[("ARIADNE_ADMIN_KEYS", "new")]);
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn requires_long_pepper() {
+        let (_, report) = config_from(&[("RATE_LIMIT_PEPPER", "")]);
+        assert!(report
+            .to_string()
+            .contains("`RATE_LIMIT_PEPPER` is missing"));
+
+        let (_, report) = config_from(&[("RATE_LIMIT_PEPPER", "feedbeef")]);
+        assert!(report
+            .to_string()
+            .contains("`RATE_LIMIT_PEPPER` must be at least 32 characters"));
+    }
+
+    #[test]
+    fn requires_maxmind_source() {
+        let (_, report) = config_from(&[("MAXMIND_LICENSE_KEY", "")]);
+
+        assert!(report
+            .to_string()
+            .contains("`MAXMIND_LICENSE_KEY` or `MAXMIND_LOCAL_DB` must be set"));
+    }
+
+    #[test]
+    fn requires_labrinth_to_verify_ownership() {
+        let (_, report) = config_from(&[("VERIFY_DOWNLOAD_OWNERSHIP", "true")]);
+
+        assert!(report
+            .to_string()
+            .contains("`VERIFY_DOWNLOAD_OWNERSHIP` requires `LABRINTH_API_URL`"));
+    }
+
+    #[test]
+    fn requires_rate_limit_key_with_labrinth() {
+        let (_, report) = config_from(&[("LABRINTH_API_URL", "https://api.modrinth.com/v2/")]);
+        assert!(report
+            .to_string()
+            .contains("`LABRINTH_API_URL` requires `LABRINTH_RATE_LIMIT_KEY`"));
+
+        let (config, report) = config_from(&[("LABRINTH_RATE_LIMIT_KEY", "key")]);
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(config.labrinth_rate_limit_key, "key");
+
+        let (_, report) = config_from(&[
+            ("LABRINTH_API_URL", "https://api.modrinth.com/v2/"),
+            ("LABRINTH_RATE_LIMIT_KEY", "key"),
+        ]);
+        assert!(report.is_ok(), "{report}");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::util::config::Config;
+use std::collections::HashSet;
+use url::Url;
+
+/// The CORS origin allowlist, built once at startup from `CORS_ALLOWED_ORIGINS`
+pub struct AllowedOrigins {
+    any: bool,
+    origins: HashSet<String>,
+    // Only populated when `CORS_ALLOW_SUBDOMAINS` is set
+    parent_origins: Vec<Url>,
+}
+
+impl AllowedOrigins {
+    pub fn new(config: &Config) -> Self {
+        let origins = &config.cors_allowed_origins;
+
+        AllowedOrigins {
+            any: origins.iter().any(|x| x == "*"),
+            parent_origins: if config.cors_allow_subdomains {
+                origins.iter().filter_map(|x| Url::parse(x).ok()).collect()
+            } else {
+                Vec::new()
+            },
+            origins: origins
+                .iter()
+                .map(|x| x.trim_end_matches('/').to_string())
+                .collect(),
+        }
+    }
+
+    /// Whether an entry of `CORS_ALLOWED_ORIGINS` is `*` or a bare `scheme://host[:port]` origin
+    pub fn is_valid(origin: &str) -> bool {
+        origin == "*"
+            || Url::parse(origin).is_ok_and(|url| {
+                url.host_str().is_some()
+                    && url.origin().ascii_serialization() == origin.trim_end_matches('/')
+            })
+    }
+
+    pub fn allows_any(&self) -> bool {
+        self.any
+    }
+
+    pub fn is_allowed(&self, origin: &str) -> bool {
+        if self.any || self.origins.contains(origin) {
+            return true;
+        }
+
+        if self.parent_origins.is_empty() {
+            return false;
+        }
+
+        let origin = match Url::parse(origin) {
+            Ok(origin) => origin,
+            Err(_) => return false,
+        };
+
+        self.parent_origins
+            .iter()
+            .any(|parent| match (origin.host_str(), parent.host_str()) {
+                (Some(host), Some(parent_host)) => {
+                    origin.scheme() == parent.scheme()
+                        && origin.port() == parent.port()
+                        && host.ends_with(&format!(".{parent_host}"))
+                }
+                _ => false,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::tests::config_from;
+
+    fn allowed_origins(origins: &str, allow_subdomains: bool) -> AllowedOrigins {
+        let (config, _) = config_from(&[
+            ("CORS_ALLOWED_ORIGINS", origins),
+            (
+                "CORS_ALLOW_SUBDOMAINS",
+                if allow_subdomains { "true" } else { "false" },
+            ),
+        ]);
+
+        AllowedOrigins::new(&config)
+    }
+
+    #[test]
+    fn allows_listed_origins() {
+        let origins = allowed_origins(r#"["https://modrinth.com/"]"#, false);
+
+        assert!(!origins.allows_any());
+        assert!(origins.is_allowed("https://modrinth.com"));
+        assert!(!origins.is_allowed("http://modrinth.com"));
+        assert!(!origins.is_allowed("https://staging.modrinth.com"));
+        assert!(!origins.is_allowed("https://evilmodrinth.com"));
+    }
+
+    #[test]
+    fn allows_subdomains_when_enabled() {
+        let origins = allowed_origins(r#"["https://modrinth.com"]"#, true);
+
+        assert!(origins.is_allowed("https://staging.modrinth.com"));
+        assert!(origins.is_allowed("https://a.b.modrinth.com"));
+        assert!(!origins.is_allowed("https://evilmodrinth.com"));
+        assert!(!origins.is_allowed("http://staging.modrinth.com"));
+        assert!(!origins.is_allowed("https://staging.modrinth.com:8080"));
+    }
+
+    #[test]
+    fn allows_any_origin_with_wildcard() {
+        let origins = allowed_origins(r#"["*"]"#, false);
+
+        assert!(origins.allows_any());
+        assert!(origins.is_allowed("https://example.com"));
+    }
+
+    #[test]
+    fn validates_origins() {
+        assert!(AllowedOrigins::is_valid("*"));
+        assert!(AllowedOrigins::is_valid("https://modrinth.com"));
+        assert!(AllowedOrigins::is_valid("http://localhost:3000/"));
+        assert!(!AllowedOrigins::is_valid("https://modrinth.com/mods"));
+        assert!(!AllowedOrigins::is_valid("modrinth.com"));
+    }
+}
@@ -1,10 +1,75 @@
 use std::str::FromStr;
 
-pub fn parse_var<T: FromStr>(var: &'static str) -> Option<T> {
-    dotenvy::var(var).ok().and_then(|i| i.parse().ok())
-}
 pub fn parse_strings_from_var(var: &'static str) -> Option<Vec<String>> {
     dotenvy::var(var)
         .ok()
         .and_then(|s| serde_json::from_str::<Vec<String>>(&s).ok())
 }
+
+/// Collects every problem with the configuration, so they can all be reported at once instead of
+/// failing on the first one
+#[derive(Default)]
+pub struct ConfigReport {
+    problems: Vec<String>,
+}
+
+impl ConfigReport {
+    /// Checks that a variable is set and parses as `T`
+    pub fn required<T: FromStr>(&mut self, var: &'static str) -> Option<T> {
+        if dotenvy::var(var).is_err() {
+            self.problem(format!("`{var}` is missing"));
+            return None;
+        }
+
+        self.optional(var)
+    }
+
+    /// Checks that a variable parses as `T` if it is set
+    pub fn optional<T: FromStr>(&mut self, var: &'static str) -> Option<T> {
+        let value = dotenvy::var(var).ok()?;
+
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            self.problem(format!(
+                "`{var}` is not of type `{}`",
+                std::any::type_name::<T>()
+            ));
+        }
+        parsed
+    }
+
+    /// Checks that a variable parses as `T` and meets the requirement if it is set
+    pub fn optional_where<T: FromStr>(
+        &mut self,
+        var: &'static str,
+        requirement: &str,
+        valid: impl FnOnce(&T) -> bool,
+    ) -> Option<T> {
+        let value = self.optional(var)?;
+
+        if valid(&value) {
+            Some(value)
+        } else {
+            self.problem(format!("`{var}` {requirement}"));
+            None
+        }
+    }
+
+    pub fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl std::fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "found {} problem(s):", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use crate::util::config::Config;
+use crate::util::metrics::Metrics;
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Events are best-effort, so a slow broker isn't waited on for long
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where events are published, selected with `EVENT_BROKER`
+pub enum EventBroker {
+    /// Published to NATS subjects
+    Nats(async_nats::Client),
+    /// Produced to Kafka topics through the Kafka REST proxy at `url`
+    Kafka {
+        client: reqwest::Client,
+        url: String,
+    },
+}
+
+/// Publishes every ingested download and page view as JSON to a message broker, for systems that
+/// want the raw event stream without querying ClickHouse. Publishing happens in the background
+/// alongside the normal queue, and events that fail to publish are only counted in the metrics.
+/// Does nothing when no broker is configured.
+pub struct EventPublisher {
+    broker: Option<EventBroker>,
+    // Events of each kind go to `{topic_prefix}.{kind}`, e.g. `ariadne.downloads`
+    topic_prefix: String,
+    metrics: Arc<Metrics>,
+}
+
+impl EventPublisher {
+    pub async fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        let url = config.event_broker_url.clone();
+
+        let broker = match (config.event_broker.as_deref(), url) {
+            (Some("nats"), Some(url)) => {
+                info!("Connecting to NATS to publish events");
+                match async_nats::connect(&url).await {
+                    Ok(client) => Some(EventBroker::Nats(client)),
+                    Err(e) => {
+                        warn!(
+                            "Connecting to NATS failed, events won't be published: {}",
+                            e
+                        );
+                        None
+                    }
+                }
+            }
+            (Some("kafka"), Some(url)) => Some(EventBroker::Kafka {
+                client: reqwest::Client::builder()
+                    .timeout(PUBLISH_TIMEOUT)
+                    .build()
+                    .unwrap(),
+                url: url.trim_end_matches('/').to_string(),
+            }),
+            _ => None,
+        };
+
+        EventPublisher {
+            broker,
+            topic_prefix: config.event_topic_prefix.clone(),
+            metrics,
+        }
+    }
+
+    /// Publishes an event of the given kind (`downloads` or `views`) without waiting for the broker
+    pub fn publish<T: Serialize>(&self, kind: &'static str, event: &T) {
+        let broker = match &self.broker {
+            Some(broker) => broker,
+            None => return,
+        };
+
+        let event = match serde_json::to_value(event) {
+            Ok(event) => event,
+            Err(_) => {
+                self.metrics.record_event_dropped(kind);
+                return;
+            }
+        };
+
+        let topic = format!("{}.{}", self.topic_prefix, kind);
+        let metrics = self.metrics.clone();
+
+        match broker {
+            EventBroker::Nats(client) => {
+                let client = client.clone();
+
+                actix_web::rt::spawn(async move {
+                    let published = tokio::time::timeout(
+                        PUBLISH_TIMEOUT,
+                        client.publish(topic, event.to_string().into()),
+                    )
+                    .await;
+
+                    if !matches!(published, Ok(Ok(()))) {
+                        debug!("Publishing a {} event to NATS failed", kind);
+                        metrics.record_event_dropped(kind);
+                    }
+                });
+            }
+            EventBroker::Kafka { client, url } => {
+                let request = client
+                    .post(format!("{url}/topics/{topic}"))
+                    .header("Content-Type", "application/vnd.kafka.json.v2+json")
+                    .body(json!({ "records": [{ "value": event }] }).to_string());
+
+                actix_web::rt::spawn(async move {
+                    let produced = request
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status());
+
+                    if let Err(e) = produced {
+                        debug!("Producing a {} event to Kafka failed: {}", kind, e);
+                        metrics.record_event_dropped(kind);
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::util::base62::parse_base62;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+const FLAGS_VAR: &str = "FEATURE_FLAGS";
+
+#[derive(Deserialize)]
+struct FlagConfig {
+    // Percentage (0-100) of traffic the flag is enabled for
+    #[serde(default)]
+    percentage: u8,
+    // Base62 IDs of projects the flag is always enabled for
+    #[serde(default)]
+    projects: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Flag {
+    percentage: u8,
+    projects: HashSet<u64>,
+}
+
+/// Feature flags for gradually rolling out analytics behavior, configured through the
+/// `FEATURE_FLAGS` variable as a JSON object of flag name to
+/// `{ "percentage": 0-100, "projects": ["<base62 ID>", ..] }`. Flags that aren't configured are
+/// disabled.
+pub struct FeatureFlags {
+    flags: RwLock<HashMap<String, Flag>>,
+}
+
+impl FeatureFlags {
+    pub fn new() -> Self {
+        let flags = FeatureFlags {
+            flags: RwLock::new(HashMap::new()),
+        };
+        flags.refresh();
+
+        flags
+    }
+
+    /// Reloads the flags. The `.env` file is re-read, so edits to it apply without a restart.
+    pub fn refresh(&self) {
+        let raw = dotenvy::dotenv_iter()
+            .ok()
+            .and_then(|mut vars| {
+                vars.find_map(|x| x.ok().filter(|(key, _)| key == FLAGS_VAR))
+                    .map(|(_, value)| value)
+            })
+            .or_else(|| std::env::var(FLAGS_VAR).ok());
+
+        let configs = match raw {
+            Some(raw) => match serde_json::from_str::<HashMap<String, FlagConfig>>(&raw) {
+                Ok(configs) => configs,
+                Err(e) => {
+                    warn!(
+                        "Unable to parse `{}`, keeping previous flags: {}",
+                        FLAGS_VAR, e
+                    );
+                    return;
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let flags = configs
+            .into_iter()
+            .map(|(name, config)| {
+                let flag = Flag {
+                    percentage: config.percentage.min(100),
+                    projects: config
+                        .projects
+                        .iter()
+                        .filter_map(|x| parse_base62(x).ok())
+                        .collect(),
+                };
+
+                (name, flag)
+            })
+            .collect();
+
+        if let Ok(mut current) = self.flags.write() {
+            *current = flags;
+        }
+    }
+
+    /// The currently active flags, as JSON
+    pub fn to_json(&self) -> serde_json::Value {
+        self.flags
+            .read()
+            .ok()
+            .and_then(|flags| serde_json::to_value(&*flags).ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether `flag` applies to a data point of `project_id`. `key` buckets the data point into
+    /// the flag's rollout percentage, so the same key consistently lands on the same side.
+    pub fn is_enabled(&self, flag: &str, project_id: u64, key: u64) -> bool {
+        let flags = match self.flags.read() {
+            Ok(flags) => flags,
+            Err(_) => return false,
+        };
+
+        flags.get(flag).is_some_and(|flag| {
+            flag.projects.contains(&project_id) || key % 100 < flag.percentage as u64
+        })
+    }
+}
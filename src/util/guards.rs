@@ -1,11 +1,87 @@
 use actix_web::guard::GuardContext;
+use log::warn;
+use std::sync::{Once, OnceLock};
 
 pub const ADMIN_KEY_HEADER: &str = "Modrinth-Admin";
-pub fn admin_key_guard(ctx: &GuardContext) -> bool {
-    let admin_key = dotenvy::var("ARIADNE_ADMIN_KEY").expect("No admin key provided!");
 
+// Set once at startup from the config, as guards can't reach app data
+static ADMIN_KEYS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Sets the accepted admin keys. Only the first call has an effect.
+pub fn set_admin_keys(keys: Vec<String>) {
+    let _ = ADMIN_KEYS.set(keys);
+}
+
+/// Whether the presented key matches any admin key
+pub fn is_admin_key(presented: &[u8]) -> bool {
+    let admin_keys = ADMIN_KEYS.get().map(Vec::as_slice).unwrap_or_default();
+
+    if admin_keys.is_empty() {
+        static MISSING_KEY: Once = Once::new();
+        MISSING_KEY.call_once(|| {
+            warn!("No admin keys are set, denying all internal routes");
+        });
+    }
+
+    matches_any_key(admin_keys, presented)
+}
+
+/// Whether the presented key matches any of the keys, each compared in constant time
+fn matches_any_key(keys: &[String], presented: &[u8]) -> bool {
+    // Every key is compared, so the timing doesn't reveal which one matched
+    keys.iter().fold(false, |acc, key| {
+        acc | constant_time_eq(presented, key.as_bytes())
+    })
+}
+
+/// Allows requests presenting an admin key. If no key is configured, every request is denied
+/// rather than panicking the worker.
+pub fn admin_key_guard(ctx: &GuardContext) -> bool {
     ctx.head()
         .headers()
         .get(ADMIN_KEY_HEADER)
-        .map_or(false, |it| it.as_bytes() == admin_key.as_bytes())
+        .is_some_and(|it| is_admin_key(it.as_bytes()))
+}
+
+/// Compares two byte strings in time that depends only on their lengths, so comparing a secret
+/// like the admin key doesn't leak how much of it a guess got right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_keys() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn denies_without_keys() {
+        assert!(!matches_any_key(&[], b"secret"));
+        assert!(!matches_any_key(&[], b""));
+    }
+
+    #[test]
+    fn accepts_any_key_while_rotating() {
+        let keys = ["old".to_string(), "new".to_string()];
+
+        assert!(matches_any_key(&keys, b"old"));
+        assert!(matches_any_key(&keys, b"new"));
+        assert!(!matches_any_key(&keys, b"other"));
+
+        // Once the old key is retired, only the new one is accepted
+        let keys = ["new".to_string()];
+        assert!(!matches_any_key(&keys, b"old"));
+        assert!(matches_any_key(&keys, b"new"));
+    }
 }
@@ -0,0 +1,69 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Stored as the country of IPs that can't be geolocated because they aren't publicly routable
+pub const PRIVATE_COUNTRY: &str = "XX";
+
+/// Whether an IP isn't publicly routable, so geolocating it is pointless: loopback, private
+/// (RFC 1918 or IPv6 unique local), link-local or unspecified. IPv4-mapped IPs are checked as IPv4.
+pub fn is_private(ip: Ipv6Addr) -> bool {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return is_private_v4(ip);
+    }
+
+    let first = ip.segments()[0];
+
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10
+        || (first & 0xffc0) == 0xfe80
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> Ipv6Addr {
+        match ip.parse().unwrap() {
+            std::net::IpAddr::V4(x) => x.to_ipv6_mapped(),
+            std::net::IpAddr::V6(x) => x,
+        }
+    }
+
+    #[test]
+    fn detects_private_ips() {
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+        ] {
+            assert!(is_private(ip(private)), "{private}");
+        }
+    }
+
+    #[test]
+    fn detects_public_ips() {
+        for public in [
+            "1.1.1.1",
+            "172.32.0.1",
+            "8.8.8.8",
+            "2606:4700::1111",
+            "fec0::1",
+        ] {
+            assert!(!is_private(ip(public)), "{public}");
+        }
+    }
+}
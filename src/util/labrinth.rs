@@ -0,0 +1,175 @@
+use crate::util::config::Config;
+use crate::util::metrics::Metrics;
+use log::debug;
+use reqwest::{RequestBuilder, Response};
+use std::time::{Duration, Instant};
+
+/// Builds the client every labrinth request is sent with. It is shared, so connections to labrinth
+/// are pooled and reused across requests. Requests are abandoned after `timeout`, so a hung
+/// labrinth can't tie up workers indefinitely.
+pub fn client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(timeout.min(Duration::from_secs(2)))
+        .timeout(timeout)
+        .build()
+        .unwrap()
+}
+
+/// Sends a request to labrinth, logging and recording how long each attempt took to respond under
+/// `route`, so slow ingests can be told apart from slow labrinth responses. Connection failures
+/// and server errors are retried up to `LABRINTH_RETRY_ATTEMPTS` times in total, with a jittered
+/// delay starting at `LABRINTH_RETRY_BASE_MS` and doubling after each attempt.
+pub async fn send(
+    config: &Config,
+    metrics: &Metrics,
+    route: &'static str,
+    mut request: RequestBuilder,
+) -> Result<Response, reqwest::Error> {
+    let attempts = config.labrinth_retry_attempts.max(1);
+    let mut delay = config.labrinth_retry_base;
+
+    let mut attempt = 1;
+    loop {
+        // Requests without a streaming body can always be cloned
+        let retry = request.try_clone().filter(|_| attempt < attempts);
+
+        let start = Instant::now();
+        let response = request.send().await;
+        let latency = start.elapsed();
+
+        debug!("labrinth `{}` responded in {:?}", route, latency);
+        metrics.observe_labrinth_latency(route, latency);
+
+        let retryable = match &response {
+            Ok(response) => response.status().is_server_error(),
+            // Timeouts aren't retried, as they already took as long as a request may
+            Err(e) => !e.is_timeout() && (e.is_connect() || e.is_request()),
+        };
+
+        match retry {
+            Some(retry) if retryable => {
+                debug!("Retrying labrinth `{}` (attempt {})", route, attempt);
+
+                tokio::time::sleep(jitter(delay)).await;
+                delay *= 2;
+                attempt += 1;
+                request = retry;
+            }
+            _ => return response,
+        }
+    }
+}
+
+/// Randomizes a delay between half and one and a half times its length, so requests that failed
+/// together don't all retry at once
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(0.5 + rand::random::<f64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::ApiError;
+    use crate::util::config::tests::config_from;
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[actix_rt::test]
+    async fn times_out_as_gateway_timeout() {
+        let labrinth = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .expect(1)
+            .mount(&labrinth)
+            .await;
+
+        let (config, _) = config_from(&[("LABRINTH_TIMEOUT_MS", "100")]);
+        let request = client(config.labrinth_timeout).get(format!("{}/user", labrinth.uri()));
+        let error = send(&config, &Metrics::new().unwrap(), "user", request)
+            .await
+            .unwrap_err();
+
+        assert!(error.is_timeout());
+        assert_eq!(
+            ApiError::Api(error).status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+
+    #[actix_rt::test]
+    async fn retries_server_errors_with_backoff() {
+        let labrinth = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&labrinth)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&labrinth)
+            .await;
+
+        let (config, _) = config_from(&[
+            ("LABRINTH_RETRY_ATTEMPTS", "3"),
+            ("LABRINTH_RETRY_BASE_MS", "100"),
+        ]);
+        let request = reqwest::Client::new().get(format!("{}/user", labrinth.uri()));
+
+        let start = Instant::now();
+        let response = send(&config, &Metrics::new().unwrap(), "user", request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        // The delays are at least half of 100ms and then of 200ms
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[actix_rt::test]
+    async fn gives_up_after_the_last_attempt() {
+        let labrinth = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&labrinth)
+            .await;
+
+        let (config, _) = config_from(&[
+            ("LABRINTH_RETRY_ATTEMPTS", "3"),
+            ("LABRINTH_RETRY_BASE_MS", "1"),
+        ]);
+        let request = reqwest::Client::new().get(format!("{}/user", labrinth.uri()));
+        let response = send(&config, &Metrics::new().unwrap(), "user", request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 500);
+    }
+
+    #[actix_rt::test]
+    async fn does_not_retry_client_errors() {
+        let labrinth = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/user"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&labrinth)
+            .await;
+
+        let (config, _) = config_from(&[("LABRINTH_RETRY_ATTEMPTS", "3")]);
+        let request = reqwest::Client::new().get(format!("{}/user", labrinth.uri()));
+        let response = send(&config, &Metrics::new().unwrap(), "user", request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 404);
+    }
+}
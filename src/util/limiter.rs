@@ -0,0 +1,34 @@
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Bounds how many requests may wait on an upstream service at once
+pub struct ConcurrencyLimiter {
+    semaphore: Semaphore,
+    permits: usize,
+    max_wait: Duration,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(permits: usize, max_wait: Duration) -> Self {
+        let permits = permits.max(1);
+
+        ConcurrencyLimiter {
+            semaphore: Semaphore::new(permits),
+            permits,
+            max_wait,
+        }
+    }
+
+    /// Waits up to `max_wait` for a permit, returning `None` if the limiter stayed saturated
+    pub async fn acquire(&self) -> Option<SemaphorePermit<'_>> {
+        tokio::time::timeout(self.max_wait, self.semaphore.acquire())
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// The fraction of permits currently in use
+    pub fn saturation(&self) -> f64 {
+        (self.permits - self.semaphore.available_permits()) as f64 / self.permits as f64
+    }
+}
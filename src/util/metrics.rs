@@ -0,0 +1,184 @@
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Why a page view was or wasn't recorded
+#[derive(Clone, Copy)]
+pub enum ViewOutcome {
+    Counted,
+    DisallowedDomain,
+    RateLimited,
+    StorageUnavailable,
+}
+
+impl ViewOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ViewOutcome::Counted => "counted",
+            ViewOutcome::DisallowedDomain => "disallowed_domain",
+            ViewOutcome::RateLimited => "rate_limited",
+            ViewOutcome::StorageUnavailable => "storage_unavailable",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    view_outcomes: IntCounterVec,
+    labrinth_saturation: Gauge,
+    labrinth_saturated: IntCounter,
+    labrinth_latency: HistogramVec,
+    events_dropped: IntCounterVec,
+    queue_entries: IntGauge,
+    queue_dropped: IntGauge,
+    ingested: IntCounterVec,
+    flush_duration: Histogram,
+    flush_failures: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("ariadne".to_string()), None)?;
+
+        let view_outcomes = IntCounterVec::new(
+            Opts::new(
+                "page_view_outcomes_total",
+                "Page views received, by whether they were counted or why not",
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(view_outcomes.clone()))?;
+
+        let labrinth_saturation = Gauge::new(
+            "labrinth_saturation",
+            "Fraction of concurrent labrinth lookup permits in use during page view ingest",
+        )?;
+        registry.register(Box::new(labrinth_saturation.clone()))?;
+
+        let labrinth_saturated = IntCounter::new(
+            "labrinth_saturated_total",
+            "Page views recorded without project attribution because labrinth was saturated",
+        )?;
+        registry.register(Box::new(labrinth_saturated.clone()))?;
+
+        let labrinth_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "labrinth_request_duration_seconds",
+                "Time until labrinth responded, by which labrinth route was called",
+            ),
+            &["route"],
+        )?;
+        registry.register(Box::new(labrinth_latency.clone()))?;
+
+        let events_dropped = IntCounterVec::new(
+            Opts::new(
+                "events_dropped_total",
+                "Events that couldn't be published to the message broker, by kind",
+            ),
+            &["kind"],
+        )?;
+        registry.register(Box::new(events_dropped.clone()))?;
+
+        let queue_entries = IntGauge::new(
+            "analytics_queue_entries",
+            "Data points waiting in the analytics queue for the next flush",
+        )?;
+        registry.register(Box::new(queue_entries.clone()))?;
+
+        let queue_dropped = IntGauge::new(
+            "analytics_queue_dropped",
+            "Data points dropped since startup because the analytics queue was full",
+        )?;
+        registry.register(Box::new(queue_dropped.clone()))?;
+
+        let ingested = IntCounterVec::new(
+            Opts::new(
+                "ingested_total",
+                "Data points added to the analytics queue, by kind",
+            ),
+            &["kind"],
+        )?;
+        registry.register(Box::new(ingested.clone()))?;
+
+        let flush_duration = Histogram::with_opts(HistogramOpts::new(
+            "analytics_flush_duration_seconds",
+            "Time taken to flush the analytics queue to ClickHouse, including failed flushes",
+        ))?;
+        registry.register(Box::new(flush_duration.clone()))?;
+
+        let flush_failures = IntCounterVec::new(
+            Opts::new(
+                "analytics_flush_failures_total",
+                "Failed analytics queue flushes, by kind of failure",
+            ),
+            &["kind"],
+        )?;
+        registry.register(Box::new(flush_failures.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            view_outcomes,
+            labrinth_saturation,
+            labrinth_saturated,
+            labrinth_latency,
+            events_dropped,
+            queue_entries,
+            queue_dropped,
+            ingested,
+            flush_duration,
+            flush_failures,
+        })
+    }
+
+    pub fn record_view_outcome(&self, outcome: ViewOutcome) {
+        self.view_outcomes
+            .with_label_values(&[outcome.as_str()])
+            .inc();
+    }
+
+    pub fn set_labrinth_saturation(&self, saturation: f64) {
+        self.labrinth_saturation.set(saturation);
+    }
+
+    pub fn record_labrinth_saturated(&self) {
+        self.labrinth_saturated.inc();
+    }
+
+    pub fn observe_labrinth_latency(&self, route: &str, latency: Duration) {
+        self.labrinth_latency
+            .with_label_values(&[route])
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn record_event_dropped(&self, kind: &str) {
+        self.events_dropped.with_label_values(&[kind]).inc();
+    }
+
+    pub fn set_queue_state(&self, entries: usize, dropped: u64) {
+        self.queue_entries.set(entries as i64);
+        self.queue_dropped.set(dropped as i64);
+    }
+
+    pub fn record_ingested(&self, kind: &str) {
+        self.ingested.with_label_values(&[kind]).inc();
+    }
+
+    /// Records a flush of the analytics queue, and the kind of its failure if it failed
+    pub fn observe_flush(&self, duration: Duration, failure: Option<&str>) {
+        self.flush_duration.observe(duration.as_secs_f64());
+
+        if let Some(failure) = failure {
+            self.flush_failures.with_label_values(&[failure]).inc();
+        }
+    }
+
+    /// Renders every registered metric in the Prometheus text format
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+}
@@ -1,4 +1,24 @@
 pub mod auth;
 pub mod base62;
+pub mod cache;
+pub mod circuit;
+pub mod config;
+pub mod cors;
 pub mod env;
+pub mod events;
+pub mod flags;
 pub mod guards;
+pub mod ip;
+pub mod labrinth;
+pub mod limiter;
+pub mod metrics;
+pub mod paths;
+pub mod prefetch;
+pub mod proxies;
+pub mod request_id;
+pub mod state;
+pub mod stream;
+pub mod teams;
+pub mod time;
+pub mod user_agent;
+pub mod users;
@@ -0,0 +1,44 @@
+use crate::util::config::Config;
+
+/// How site paths are stored, set once at startup. With `SITE_PATH_SEGMENTS` set, paths are
+/// cut down to their first segments (e.g. `/mod/foo/versions/1.2.3` to `/mod/foo` with 2), so
+/// deep paths don't fragment analytics. `STORE_FULL_PATH` keeps the uncut path in a separate
+/// column for detailed path analytics.
+pub struct SitePaths {
+    segments: Option<usize>,
+    store_full: bool,
+}
+
+impl SitePaths {
+    pub fn new(config: &Config) -> Self {
+        SitePaths {
+            segments: config.site_path_segments,
+            store_full: config.store_full_path,
+        }
+    }
+
+    /// The path to store as the site path
+    pub fn normalize(&self, path: &str) -> String {
+        match self.segments {
+            Some(segments) => {
+                let segments = path
+                    .split('/')
+                    .filter(|x| !x.is_empty())
+                    .take(segments)
+                    .collect::<Vec<_>>();
+
+                format!("/{}", segments.join("/"))
+            }
+            None => path.to_string(),
+        }
+    }
+
+    /// The path to store as the full path, empty unless full paths are stored
+    pub fn full(&self, path: &str) -> String {
+        if self.store_full {
+            path.to_string()
+        } else {
+            String::new()
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use crate::util::config::Config;
+use std::collections::HashMap;
+
+// Set by browsers on prefetches and speculative loads, e.g. `Sec-Purpose: prefetch;prerender`
+const DEFAULT_PREFETCH_HEADERS: &[&str] = &["purpose", "sec-purpose"];
+
+/// Detects prefetch requests from the headers forwarded with a download, set once at startup from
+/// `PREFETCH_HEADERS`
+pub struct PrefetchDetector {
+    // Lowercase names of the headers that mark a request as a prefetch
+    headers: Vec<String>,
+}
+
+impl PrefetchDetector {
+    pub fn new(config: &Config) -> Self {
+        PrefetchDetector {
+            headers: config
+                .prefetch_headers
+                .clone()
+                .unwrap_or_else(|| {
+                    DEFAULT_PREFETCH_HEADERS
+                        .iter()
+                        .map(|x| x.to_string())
+                        .collect()
+                })
+                .into_iter()
+                .map(|x| x.to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Whether any of the detection headers has a value mentioning `prefetch`
+    pub fn is_prefetch(&self, headers: &HashMap<String, String>) -> bool {
+        headers.iter().any(|(key, value)| {
+            self.headers.contains(&key.to_lowercase()) && value.to_lowercase().contains("prefetch")
+        })
+    }
+}
@@ -0,0 +1,162 @@
+use crate::util::config::Config;
+use crate::util::guards::constant_time_eq;
+use actix_web::http::header::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Set by the proxy in front of ariadne (e.g. a Cloudflare transform rule) to prove a request
+/// went through it
+pub const PROXY_SECRET_HEADER: &str = "Modrinth-Proxy-Secret";
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Decides whether a request came through a trusted proxy, so its `cf-connecting-ip` or
+/// `X-Forwarded-For` header can be believed. Built once at startup from `TRUSTED_PROXIES` (a JSON
+/// array of CIDR ranges the proxy connects from) and `PROXY_SECRET`. With neither set every
+/// request is trusted, as the header can't be checked.
+pub struct TrustedProxies {
+    // (network, prefix length) of each range, with IPv4 ranges mapped into IPv6
+    ranges: Vec<(u128, u32)>,
+    secret: Option<String>,
+}
+
+impl TrustedProxies {
+    pub fn new(config: &Config) -> Self {
+        TrustedProxies {
+            ranges: config
+                .trusted_proxies
+                .iter()
+                .filter_map(|x| parse_range(x))
+                .collect(),
+            secret: config.proxy_secret.clone(),
+        }
+    }
+
+    /// Whether an entry of `TRUSTED_PROXIES` is a valid `address/prefix` range
+    pub fn is_valid(range: &str) -> bool {
+        parse_range(range).is_some()
+    }
+
+    /// Whether the request either comes from a trusted range or presents the proxy secret
+    pub fn trusts(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> bool {
+        if self.ranges.is_empty() && self.secret.is_none() {
+            return true;
+        }
+
+        let from_range = peer.is_some_and(|peer| self.in_range(peer.ip()));
+
+        let has_secret = self.secret.as_ref().is_some_and(|secret| {
+            headers
+                .get(PROXY_SECRET_HEADER)
+                .is_some_and(|x| constant_time_eq(x.as_bytes(), secret.as_bytes()))
+        });
+
+        from_range || has_secret
+    }
+
+    /// The client IP a trusted proxy forwarded in `X-Forwarded-For`: the rightmost entry outside
+    /// the trusted ranges, as anything left of it may have been set by the client. `None` unless
+    /// the request comes from a trusted range, so a spoofed header sent directly is ignored.
+    pub fn forwarded_for(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        if !peer.is_some_and(|peer| self.in_range(peer.ip())) {
+            return None;
+        }
+
+        let entries = headers
+            .get_all(FORWARDED_FOR_HEADER)
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .collect::<Vec<_>>();
+
+        entries
+            .into_iter()
+            .rev()
+            .map(|x| x.trim().parse::<IpAddr>().ok())
+            .find(|x| x.is_none_or(|ip| !self.in_range(ip)))
+            .flatten()
+    }
+
+    fn in_range(&self, ip: IpAddr) -> bool {
+        let ip = to_bits(ip);
+
+        self.ranges
+            .iter()
+            .any(|(network, prefix)| mask(ip, *prefix) == *network)
+    }
+}
+
+fn parse_range(range: &str) -> Option<(u128, u32)> {
+    let (address, prefix) = range.split_once('/')?;
+    let address = address.parse::<IpAddr>().ok()?;
+    let prefix = prefix.parse::<u32>().ok()?;
+
+    let prefix = match address {
+        IpAddr::V4(_) if prefix <= 32 => prefix + 96,
+        IpAddr::V6(_) if prefix <= 128 => prefix,
+        _ => return None,
+    };
+
+    Some((mask(to_bits(address), prefix), prefix))
+}
+
+fn to_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(x) => u128::from(x.to_ipv6_mapped()),
+        IpAddr::V6(x) => u128::from(x),
+    }
+}
+
+// Keeps the top `prefix` bits
+fn mask(bits: u128, prefix: u32) -> u128 {
+    bits & u128::MAX.checked_shl(128 - prefix).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::config::tests::config_from;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn proxies() -> TrustedProxies {
+        let (config, report) =
+            config_from(&[("TRUSTED_PROXIES", r#"["10.0.0.0/8", "2001:db8::/32"]"#)]);
+        assert!(report.is_ok(), "{report}");
+
+        TrustedProxies::new(&config)
+    }
+
+    fn forwarded_for(peer: &str, header: &str) -> Option<IpAddr> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static(FORWARDED_FOR_HEADER),
+            HeaderValue::from_str(header).unwrap(),
+        );
+
+        proxies().forwarded_for(Some(SocketAddr::new(peer.parse().unwrap(), 443)), &headers)
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        assert_eq!(forwarded_for("203.0.113.7", "198.51.100.1"), None);
+    }
+
+    #[test]
+    fn strips_only_trusted_hops() {
+        // The client prepended a spoofed entry, then two proxies in the trusted ranges forwarded it
+        assert_eq!(
+            forwarded_for("10.0.0.1", "1.2.3.4, 198.51.100.1, 10.1.2.3, 2001:db8::1"),
+            Some("198.51.100.1".parse().unwrap())
+        );
+
+        // An untrusted hop is the furthest that can be believed, even if a client is left of it
+        assert_eq!(
+            forwarded_for("2001:db8::2", "198.51.100.1, 203.0.113.7"),
+            Some("203.0.113.7".parse().unwrap())
+        );
+
+        // Garbage can't be told apart from a spoofed entry, so nothing is believed
+        assert_eq!(forwarded_for("10.0.0.1", "198.51.100.1, unknown"), None);
+
+        // Every hop is a trusted proxy, so there's no client to report
+        assert_eq!(forwarded_for("10.0.0.1", "10.0.0.2"), None);
+    }
+}
@@ -0,0 +1,52 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use log::error;
+use std::future::Future;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: Uuid;
+}
+
+/// The ID of the request being handled, if any
+pub fn current() -> Option<Uuid> {
+    REQUEST_ID.try_with(|x| *x).ok()
+}
+
+/// Middleware giving each request a random ID, so a failed request can be matched with its logs
+/// and Sentry event. The ID is returned in the `x-request-id` header, and error bodies include it.
+pub fn with_request_id<S, B>(
+    req: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let id = Uuid::new_v4();
+    sentry::configure_scope(|scope| scope.set_tag("request_id", id));
+
+    let path = req.path().to_string();
+    let response = REQUEST_ID.sync_scope(id, || service.call(req));
+
+    REQUEST_ID.scope(id, async move {
+        let mut response = response.await?;
+
+        if response.status().is_server_error() {
+            error!(
+                "Request {} to {} failed with {}",
+                id,
+                path,
+                response.status()
+            );
+        }
+
+        response.headers_mut().insert(
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            HeaderValue::from_str(&id.to_string()).unwrap(),
+        );
+
+        Ok(response)
+    })
+}
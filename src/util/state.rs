@@ -0,0 +1,60 @@
+use crate::scheduled::analytics::AnalyticsQueue;
+use crate::scheduled::ratelimit::RateLimitQueue;
+use crate::util::config::Config;
+use crate::util::limiter::ConcurrencyLimiter;
+use crate::util::metrics::Metrics;
+use crate::util::teams::ProjectTeams;
+use crate::util::users::UserIds;
+use std::sync::Arc;
+
+/// The state most routes share, registered as a single `web::Data` so the ingest routes stay
+/// within the 12 extractors actix allows a handler
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub metrics: Arc<Metrics>,
+    pub analytics_queue: Arc<AnalyticsQueue>,
+    pub rate_limit_queue: Arc<RateLimitQueue>,
+    pub labrinth_limiter: Arc<ConcurrencyLimiter>,
+    pub user_ids: Arc<UserIds>,
+    pub project_teams: Arc<ProjectTeams>,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::scheduled::ratelimit::RateLimitStore;
+    use dashmap::DashMap;
+    use std::time::Duration;
+
+    /// Builds the state around the config, with an empty analytics queue and in-memory rate
+    /// limits
+    pub(crate) fn app_state(config: Config) -> AppState {
+        let config = Arc::new(config);
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let client = reqwest::Client::new();
+
+        AppState {
+            analytics_queue: Arc::new(AnalyticsQueue::new(None, None)),
+            rate_limit_queue: Arc::new(RateLimitQueue::new(
+                RateLimitStore::Memory(DashMap::new()),
+                config.rate_limit_pepper.clone(),
+                config.rate_limit_max_views,
+            )),
+            labrinth_limiter: Arc::new(ConcurrencyLimiter::new(1, Duration::from_secs(1))),
+            user_ids: Arc::new(UserIds::new(
+                Duration::from_secs(60),
+                client.clone(),
+                config.clone(),
+                metrics.clone(),
+            )),
+            project_teams: Arc::new(ProjectTeams::new(
+                Duration::from_secs(60),
+                client,
+                config.clone(),
+                metrics.clone(),
+            )),
+            config,
+            metrics,
+        }
+    }
+}
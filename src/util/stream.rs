@@ -0,0 +1,226 @@
+use crate::routes::ApiError;
+use actix_web::http::header::{ACCEPT, CONTENT_DISPOSITION};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use clickhouse::query::RowCursor;
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Streams the rows of a ClickHouse cursor as a comma-separated JSON sequence wrapped in `prefix`
+/// and `suffix`, so large results are never fully buffered in memory. `write_row` serializes a
+/// single row into the given buffer.
+pub fn json_stream<T, F>(
+    prefix: String,
+    cursor: RowCursor<T>,
+    suffix: &'static str,
+    write_row: F,
+) -> impl Stream<Item = Result<Bytes, ApiError>>
+where
+    T: DeserializeOwned + 'static,
+    F: FnMut(&T, &mut Vec<u8>) -> Result<(), serde_json::Error> + 'static,
+{
+    let rows = futures::stream::unfold(Some((cursor, write_row, true)), |state| async move {
+        let (mut cursor, mut write_row, first) = state?;
+
+        let row = match cursor.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => return None,
+            // The cursor can't be resumed after an error, so end the stream here
+            Err(e) => return Some((Err(ApiError::from(e)), None)),
+        };
+
+        let mut buf = if first { Vec::new() } else { vec![b','] };
+        let chunk = write_row(&row, &mut buf)
+            .map(|_| Bytes::from(buf))
+            .map_err(ApiError::from);
+
+        Some((chunk, Some((cursor, write_row, false))))
+    });
+
+    futures::stream::once(async move { Ok(Bytes::from(prefix)) })
+        .chain(rows)
+        .chain(futures::stream::once(async move {
+            Ok(Bytes::from_static(suffix.as_bytes()))
+        }))
+}
+
+/// Streams the rows of a ClickHouse cursor as newline-delimited JSON, one row per line, so large
+/// results are never fully buffered in memory
+pub fn ndjson_stream<T>(cursor: RowCursor<T>) -> impl Stream<Item = Result<Bytes, ApiError>>
+where
+    T: DeserializeOwned + Serialize + 'static,
+{
+    futures::stream::unfold(Some(cursor), |cursor| async move {
+        let mut cursor = cursor?;
+
+        let row = match cursor.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => return None,
+            // The cursor can't be resumed after an error, so end the stream here
+            Err(e) => return Some((Err(ApiError::from(e)), None)),
+        };
+
+        let mut buf = Vec::new();
+        let chunk = serde_json::to_writer(&mut buf, &row)
+            .map(|_| {
+                buf.push(b'\n');
+                Bytes::from(buf)
+            })
+            .map_err(ApiError::from);
+
+        Some((chunk, Some(cursor)))
+    })
+}
+
+/// The format a query's results are returned in
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// The requested format: CSV if asked for by `?format=csv` or an `Accept: text/csv` header,
+    /// otherwise JSON
+    pub fn of(req: &HttpRequest, format: Option<Format>) -> Format {
+        format.unwrap_or_else(|| {
+            let accepts_csv = req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|x| x.to_str().ok())
+                .is_some_and(|x| x.contains("text/csv"));
+
+            if accepts_csv {
+                Format::Csv
+            } else {
+                Format::Json
+            }
+        })
+    }
+}
+
+/// Serializes rows as CSV, with a header row of their field names if `headers`
+fn csv_rows<T: Serialize>(
+    rows: impl IntoIterator<Item = T>,
+    headers: bool,
+) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(headers)
+        .from_writer(Vec::new());
+
+    for row in rows {
+        writer.serialize(row)?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| csv::Error::from(e.into_error()))
+}
+
+/// Starts a response that is downloaded as a CSV file named `filename`
+pub fn csv_download(filename: &str) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/csv").insert_header((
+        CONTENT_DISPOSITION,
+        format!(r#"attachment; filename="{filename}""#),
+    ));
+    response
+}
+
+/// Responds with rows as a CSV file named `filename`, with a header row of their field names
+pub fn csv_response<T: Serialize>(
+    filename: &str,
+    rows: impl IntoIterator<Item = T>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(csv_download(filename).body(csv_rows(rows, true)?))
+}
+
+/// Streams the rows of a ClickHouse cursor as CSV, with a header row of their field names, so
+/// large results are never fully buffered in memory
+pub fn csv_stream<T>(cursor: RowCursor<T>) -> impl Stream<Item = Result<Bytes, ApiError>>
+where
+    T: DeserializeOwned + Serialize + 'static,
+{
+    futures::stream::unfold(Some((cursor, true)), |state| async move {
+        let (mut cursor, first) = state?;
+
+        let row = match cursor.next().await {
+            Ok(Some(row)) => row,
+            Ok(None) => return None,
+            // The cursor can't be resumed after an error, so end the stream here
+            Err(e) => return Some((Err(ApiError::from(e)), None)),
+        };
+
+        let chunk = csv_rows([row], first)
+            .map(Bytes::from)
+            .map_err(ApiError::from);
+
+        Some((chunk, Some((cursor, false))))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Row {
+        project_id: u64,
+        page_views: u64,
+    }
+
+    #[test]
+    fn streams_one_csv_header() {
+        let rows = || {
+            (1..=3).map(|x| Row {
+                project_id: x,
+                page_views: x * 10,
+            })
+        };
+
+        // The chunks `csv_stream` sends, one per row with only the first carrying the header
+        let chunks = rows()
+            .enumerate()
+            .map(|(i, row)| csv_rows([row], i == 0).unwrap())
+            .collect::<Vec<_>>()
+            .concat();
+
+        assert_eq!(chunks, csv_rows(rows(), true).unwrap());
+        assert_eq!(
+            String::from_utf8(chunks).unwrap(),
+            "project_id,page_views\n1,10\n2,20\n3,30\n"
+        );
+    }
+
+    #[test]
+    fn negotiates_format() {
+        let request = |accept: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::default();
+            if let Some(accept) = accept {
+                req = req.insert_header((ACCEPT, accept));
+            }
+            req.to_http_request()
+        };
+
+        assert_eq!(Format::of(&request(None), None), Format::Json);
+        assert_eq!(
+            Format::of(&request(Some("application/json")), None),
+            Format::Json
+        );
+        assert_eq!(Format::of(&request(Some("text/csv")), None), Format::Csv);
+        assert_eq!(
+            Format::of(&request(Some("application/json, text/csv;q=0.5")), None),
+            Format::Csv
+        );
+
+        // `?format=` wins over the header
+        assert_eq!(
+            Format::of(&request(Some("text/csv")), Some(Format::Json)),
+            Format::Json
+        );
+        assert_eq!(Format::of(&request(None), Some(Format::Csv)), Format::Csv);
+    }
+}
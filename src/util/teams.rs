@@ -0,0 +1,130 @@
+use crate::routes::ApiError;
+use crate::util::auth::get_team;
+use crate::util::base62::parse_base62;
+use crate::util::cache::TtlCache;
+use crate::util::config::Config;
+use crate::util::labrinth;
+use crate::util::metrics::Metrics;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Tells whether users are on the team of a project, so their own traffic to it can be flagged.
+/// Teams are cached briefly, as every authenticated event would otherwise look one up. Also
+/// resolves the projects views and downloads are attributed to.
+pub struct ProjectTeams {
+    // project ID -> IDs of accepted team members
+    teams: TtlCache<u64, Arc<HashSet<u64>>>,
+    client: reqwest::Client,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+}
+
+impl ProjectTeams {
+    pub fn new(
+        ttl: Duration,
+        client: reqwest::Client,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        ProjectTeams {
+            teams: TtlCache::new(ttl),
+            client,
+            config,
+            metrics,
+        }
+    }
+
+    /// Whether the user is an accepted member of the project's team. `project` is the ID or slug
+    /// the team is looked up by, and `project_id` the parsed ID it is cached under.
+    pub async fn is_member(
+        &self,
+        project: &str,
+        project_id: u64,
+        user_id: u64,
+    ) -> Result<bool, ApiError> {
+        Ok(self.members(project, project_id).await?.contains(&user_id))
+    }
+
+    /// The IDs of the accepted members of the project's team, looked up like `is_member`
+    pub async fn members(
+        &self,
+        project: &str,
+        project_id: u64,
+    ) -> Result<Arc<HashSet<u64>>, ApiError> {
+        if let Some(members) = self.teams.get(&project_id) {
+            return Ok(members);
+        }
+
+        let team = get_team(&self.client, &self.config, &self.metrics, project, None).await?;
+
+        let members = Arc::new(
+            team.members
+                .iter()
+                .filter(|x| x.accepted)
+                .filter_map(|x| parse_base62(&x.user.id).ok())
+                .collect::<HashSet<_>>(),
+        );
+        self.teams.insert(project_id, members.clone());
+        Ok(members)
+    }
+
+    /// Looks up the ID of a project by ID or slug, returning `None` if labrinth doesn't know it
+    pub async fn project_id(&self, project: &str) -> Result<Option<u64>, ApiError> {
+        #[derive(Deserialize)]
+        struct CheckResponse {
+            id: String,
+        }
+
+        let request = self
+            .client
+            .get(format!(
+                "{}project/{}/check",
+                self.config.labrinth_url()?,
+                project
+            ))
+            .header("x-ratelimit-key", &self.config.labrinth_rate_limit_key);
+        let response =
+            labrinth::send(&self.config, &self.metrics, "project_check", request).await?;
+
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+
+        let check_response = response.json::<CheckResponse>().await?;
+
+        Ok(parse_base62(&check_response.id).ok())
+    }
+
+    /// Looks up the ID of the project a version belongs to, returning `None` if labrinth doesn't
+    /// know the version
+    pub async fn version_project_id(&self, version: &str) -> Result<Option<u64>, ApiError> {
+        #[derive(Deserialize)]
+        struct VersionResponse {
+            project_id: String,
+        }
+
+        let request = self
+            .client
+            .get(format!(
+                "{}version/{}",
+                self.config.labrinth_url()?,
+                version
+            ))
+            .header("x-ratelimit-key", &self.config.labrinth_rate_limit_key);
+        let response = labrinth::send(&self.config, &self.metrics, "version", request).await?;
+
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+
+        let version_response = response.json::<VersionResponse>().await?;
+
+        Ok(parse_base62(&version_response.project_id).ok())
+    }
+
+    pub fn prune(&self) {
+        self.teams.prune();
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::{DateTime, TimeZone, Utc};
+
+// `recorded` and the other DateTime64(4) columns are stored as ticks of 100 microseconds
+const TICKS_PER_SECOND: i64 = 10_000;
+const NANOS_PER_TICK: i64 = 100_000;
+
+/// The current time as a `recorded` timestamp
+pub fn record_now() -> i64 {
+    to_record_ts(Utc::now())
+}
+
+/// Converts a time to a `recorded` timestamp. Built from whole seconds, so unlike
+/// `timestamp_nanos` it doesn't overflow outside of 1677-2262.
+pub fn to_record_ts(time: DateTime<Utc>) -> i64 {
+    time.timestamp() * TICKS_PER_SECOND + i64::from(time.timestamp_subsec_nanos()) / NANOS_PER_TICK
+}
+
+/// Converts a time to a bound `recorded` columns are compared against in queries. ClickHouse reads
+/// integers compared with DateTime64 columns as whole seconds, so this is the `recorded` timestamp
+/// scaled back down, rounding towards the past.
+pub fn to_query_ts(time: DateTime<Utc>) -> i64 {
+    to_record_ts(time).div_euclid(TICKS_PER_SECOND)
+}
+
+/// Converts a `recorded` timestamp back to a time, `None` if it is out of range
+pub fn from_record_ts(ts: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(
+        ts.div_euclid(TICKS_PER_SECOND),
+        (ts.rem_euclid(TICKS_PER_SECOND) * NANOS_PER_TICK) as u32,
+    )
+    .single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times() -> Vec<DateTime<Utc>> {
+        vec![
+            Utc.timestamp(0, 0),
+            Utc.timestamp(1_700_000_000, 123_400_000),
+            Utc.timestamp(-1, 999_900_000),
+            // Out of range for `timestamp_nanos`
+            Utc.ymd(1500, 1, 1).and_hms_milli(12, 0, 0, 500),
+            Utc.ymd(2500, 12, 31).and_hms_milli(23, 59, 59, 999),
+        ]
+    }
+
+    #[test]
+    fn round_trips() {
+        for time in times() {
+            assert_eq!(from_record_ts(to_record_ts(time)), Some(time));
+        }
+
+        // Precision beyond a tick is dropped
+        let time = Utc.timestamp(1_700_000_000, 123_456_789);
+        assert_eq!(
+            from_record_ts(to_record_ts(time)),
+            Some(Utc.timestamp(1_700_000_000, 123_400_000))
+        );
+    }
+
+    #[test]
+    fn query_bounds_contain_recorded_times() {
+        for time in times() {
+            let recorded = to_record_ts(time);
+            let bound = to_query_ts(time);
+
+            assert_eq!(bound, time.timestamp());
+            // A row recorded at `time` is within a range from its query bound to the next second
+            assert!(bound * TICKS_PER_SECOND <= recorded);
+            assert!(recorded < (bound + 1) * TICKS_PER_SECOND);
+        }
+    }
+}
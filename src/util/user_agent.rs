@@ -0,0 +1,110 @@
+/// A coarse classification of the client behind a user agent, so human traffic can be told apart
+/// from automated clients without parsing user agents in every query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAgentKind {
+    Browser,
+    Bot,
+    ModrinthApp,
+    ModpackLauncher,
+    Unknown,
+}
+
+impl UserAgentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserAgentKind::Browser => "browser",
+            UserAgentKind::Bot => "bot",
+            UserAgentKind::ModrinthApp => "modrinth_app",
+            UserAgentKind::ModpackLauncher => "modpack_launcher",
+            UserAgentKind::Unknown => "unknown",
+        }
+    }
+}
+
+// Substrings of lowercased user agents, checked in this order. Launchers come before bots, as
+// some identify their HTTP library too.
+const MODRINTH_APP: &[&str] = &["modrinth/theseus", "modrinth-app", "modrinthapp"];
+const MODPACK_LAUNCHERS: &[&str] = &[
+    "prismlauncher",
+    "polymc",
+    "multimc",
+    "atlauncher",
+    "gdlauncher",
+    "curseforge",
+    "technic",
+    "ftb-app",
+    "hmcl",
+    "pojavlauncher",
+    "sklauncher",
+    "packwiz",
+    "ferium",
+];
+const BOTS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "curl",
+    "wget",
+    "python-requests",
+    "python-urllib",
+    "aiohttp",
+    "go-http-client",
+    "okhttp",
+    "axios",
+    "node-fetch",
+    "java/",
+    "apache-httpclient",
+    "libwww",
+    "headless",
+];
+
+/// Classifies a user agent by the substrings known clients identify with
+pub fn classify(user_agent: &str) -> UserAgentKind {
+    let user_agent = user_agent.to_lowercase();
+    let matches = |needles: &[&str]| needles.iter().any(|x| user_agent.contains(x));
+
+    if matches(MODRINTH_APP) {
+        UserAgentKind::ModrinthApp
+    } else if matches(MODPACK_LAUNCHERS) {
+        UserAgentKind::ModpackLauncher
+    } else if matches(BOTS) {
+        UserAgentKind::Bot
+    } else if user_agent.starts_with("mozilla/") {
+        UserAgentKind::Browser
+    } else {
+        UserAgentKind::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_user_agents() {
+        for (user_agent, kind) in [
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36",
+                UserAgentKind::Browser,
+            ),
+            (
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                UserAgentKind::Bot,
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64) HeadlessChrome/120.0.0.0",
+                UserAgentKind::Bot,
+            ),
+            ("curl/8.4.0", UserAgentKind::Bot),
+            ("python-requests/2.31.0", UserAgentKind::Bot),
+            ("modrinth/theseus/0.6.3 (support@modrinth.com)", UserAgentKind::ModrinthApp),
+            ("PrismLauncher/8.0", UserAgentKind::ModpackLauncher),
+            // Launchers that also name their HTTP library aren't bots
+            ("ATLauncher/3.4.35.0 okhttp/4.12.0", UserAgentKind::ModpackLauncher),
+            ("", UserAgentKind::Unknown),
+            ("SomeClient/1.0", UserAgentKind::Unknown),
+        ] {
+            assert_eq!(classify(user_agent), kind, "{user_agent}");
+        }
+    }
+}
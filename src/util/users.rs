@@ -0,0 +1,55 @@
+use crate::util::auth::get_user;
+use crate::util::base62::parse_base62;
+use crate::util::cache::TtlCache;
+use crate::util::config::Config;
+use crate::util::metrics::Metrics;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Resolves authorization tokens to user IDs through labrinth. Resolved users are cached briefly,
+/// so a logged in user's events don't each cost a lookup.
+pub struct UserIds {
+    // SHA-256 of the token -> user ID, so tokens themselves aren't kept in memory
+    users: TtlCache<[u8; 32], u64>,
+    client: reqwest::Client,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+}
+
+impl UserIds {
+    pub fn new(
+        ttl: Duration,
+        client: reqwest::Client,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        UserIds {
+            users: TtlCache::new(ttl),
+            client,
+            config,
+            metrics,
+        }
+    }
+
+    /// The ID of the user the token belongs to, or `None` if it couldn't be resolved
+    pub async fn resolve(&self, token: &str) -> Option<u64> {
+        let key: [u8; 32] = Sha256::digest(token).into();
+
+        if let Some(user_id) = self.users.get(&key) {
+            return Some(user_id);
+        }
+
+        let user = get_user(&self.client, &self.config, &self.metrics, token)
+            .await
+            .ok()?;
+        let user_id = parse_base62(&user.id).ok()?;
+
+        self.users.insert(key, user_id);
+        Some(user_id)
+    }
+
+    pub fn prune(&self) {
+        self.users.prune();
+    }
+}